@@ -0,0 +1,283 @@
+use crate::{
+    css::Declaration,
+    properties::{CSSWideKeyword, LonghandId, PropertyDeclaration},
+    selector::Specificity,
+    styling::{Display, Style, Unit},
+};
+
+/// Where a declaration comes from. For normal declarations later origins
+/// win, for `!important` ones earlier origins do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Origin {
+    /// Kale's own defaults
+    UserAgent,
+    /// The reader's style sheet
+    User,
+    /// The page's `<style>` elements and `style` attributes
+    Author,
+}
+
+/// A declaration that applies to an element, with what the cascade sorts
+/// it by besides source order.
+#[derive(Debug, Clone)]
+pub(crate) struct CascadedDeclaration {
+    pub declaration: Declaration,
+    pub origin: Origin,
+    /// From a `style` attribute, which wins over any selector
+    pub inline: bool,
+    /// Of the selector that matched
+    pub specificity: Specificity,
+}
+
+impl CascadedDeclaration {
+    /// Declarations sorted by this are in the order they apply in, the last
+    /// one winning.
+    fn precedence(&self) -> (u8, bool, Specificity) {
+        let level = match (self.origin, self.declaration.important) {
+            (Origin::UserAgent, false) => 0,
+            (Origin::User, false) => 1,
+            (Origin::Author, false) => 2,
+            (Origin::Author, true) => 3,
+            (Origin::User, true) => 4,
+            (Origin::UserAgent, true) => 5,
+        };
+        (level, self.inline, self.specificity)
+    }
+}
+
+/// The style of an element no declaration applies to and that has no
+/// parent to inherit from.
+pub(crate) fn initial_style() -> Style {
+    Style {
+        display: Display::Inline,
+        ..Style::default()
+    }
+}
+
+/// Computes the style of an element from the declarations that apply to
+/// it, in source order, and its parent's computed style. `root` is the
+/// computed style of the root element, for `rem` lengths, and `None` when
+/// styling the root itself. Lengths in the result are in pixels.
+pub(crate) fn cascade(
+    mut declarations: Vec<CascadedDeclaration>,
+    parent: Option<&Style>,
+    root: Option<&Style>,
+) -> Style {
+    // The sort is stable, so source order decides between equals
+    declarations.sort_by_key(CascadedDeclaration::precedence);
+    let mut winners: [Option<&PropertyDeclaration>; LonghandId::ALL.len()] =
+        [None; LonghandId::ALL.len()];
+    for cascaded in &declarations {
+        let property = &cascaded.declaration.property;
+        winners[property.id() as usize] = Some(property);
+    }
+
+    let initial = initial_style();
+    let parent = parent.unwrap_or(&initial);
+    let mut style = initial.clone();
+    // `color` comes before `text-decoration-color`, which can depend on it
+    for id in LonghandId::ALL {
+        let keyword = match winners[id as usize] {
+            Some(PropertyDeclaration::CSSWide(_, keyword)) => *keyword,
+            Some(declaration) => {
+                declaration.apply(&mut style, parent.color);
+                continue;
+            }
+            None => CSSWideKeyword::Unset,
+        };
+        let inherit = match keyword {
+            CSSWideKeyword::Inherit => true,
+            CSSWideKeyword::Initial => false,
+            CSSWideKeyword::Unset => id.inherited(),
+        };
+        if inherit {
+            id.copy(parent, &mut style);
+        }
+    }
+    // An element that decorates its text does it in its own color unless
+    // told otherwise
+    let declares_line = matches!(
+        winners[LonghandId::TextDecorationLine as usize],
+        Some(declaration) if !matches!(declaration, PropertyDeclaration::CSSWide(..))
+    );
+    if declares_line && winners[LonghandId::TextDecorationColor as usize].is_none() {
+        style.text_decoration.color = style.color;
+    }
+
+    compute_lengths(&mut style, parent, root);
+    style
+}
+
+/// Turns `em` and `rem` lengths into pixels, so children inherit the size
+/// instead of the factor.
+fn compute_lengths(style: &mut Style, parent: &Style, root: Option<&Style>) {
+    let parent_size = parent.font.size.computed();
+    // On the root, `rem` is the initial font size in `font-size` and its
+    // own everywhere else
+    let root_size = root.map(|root| root.font.size.computed());
+    let size = style
+        .font
+        .size
+        .to_pixels(parent_size, root_size.unwrap_or(parent_size));
+    style.font.size = Unit::Px(size);
+    let root_size = root_size.unwrap_or(size);
+    let margin = &mut style.margin;
+    for side in [
+        &mut margin.top,
+        &mut margin.right,
+        &mut margin.bottom,
+        &mut margin.left,
+    ] {
+        *side = Unit::Px(side.to_pixels(size, root_size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::css;
+
+    /// The declarations of a `style` attribute as if they came from a rule
+    /// with `specificity`.
+    fn declared(css: &str, origin: Origin, specificity: Specificity) -> Vec<CascadedDeclaration> {
+        css::parse_style_attribute(css)
+            .into_iter()
+            .map(|declaration| CascadedDeclaration {
+                declaration,
+                origin,
+                inline: false,
+                specificity,
+            })
+            .collect()
+    }
+
+    fn color(declarations: Vec<CascadedDeclaration>) -> String {
+        cascade(declarations, None, None).color.to_string()
+    }
+
+    #[test]
+    fn later_origins_win_and_important_reverses_them() {
+        let low = Specificity(0, 0, 1);
+        let high = Specificity(1, 0, 0);
+        let user_agent = |css| declared(css, Origin::UserAgent, high);
+        let user = |css| declared(css, Origin::User, high);
+        let author = |css| declared(css, Origin::Author, low);
+
+        let normal = [
+            user_agent("color: red"),
+            user("color: lime"),
+            author("color: blue"),
+        ];
+        assert_eq!(color(normal.concat()), "#0000ff");
+        // Origin matters more than specificity or source order
+        let reversed = [
+            author("color: blue"),
+            user("color: lime"),
+            user_agent("color: red"),
+        ];
+        assert_eq!(color(reversed.concat()), "#0000ff");
+
+        let important = [
+            user_agent("color: red !important"),
+            user("color: lime !important"),
+            author("color: blue !important"),
+        ];
+        assert_eq!(color(important.concat()), "#ff0000");
+        let important = [
+            user("color: lime !important"),
+            author("color: blue !important"),
+        ];
+        assert_eq!(color(important.concat()), "#00ff00");
+        // Important author declarations beat normal ones of any origin
+        let mixed = [
+            user_agent("color: red"),
+            author("color: blue !important"),
+            user("color: lime"),
+        ];
+        assert_eq!(color(mixed.concat()), "#0000ff");
+    }
+
+    #[test]
+    fn inline_declarations_beat_any_selector() {
+        let mut inline = declared("color: red", Origin::Author, Specificity::default());
+        inline[0].inline = true;
+        let rule = declared("color: blue", Origin::Author, Specificity(1, 1, 1));
+        assert_eq!(color([inline.clone(), rule.clone()].concat()), "#ff0000");
+        let important = declared(
+            "color: blue !important",
+            Origin::Author,
+            Specificity(0, 0, 1),
+        );
+        assert_eq!(color([important, inline].concat()), "#0000ff");
+    }
+
+    #[test]
+    fn specificity_ties_go_to_the_later_declaration() {
+        let rule = |css| declared(css, Origin::Author, Specificity(0, 1, 0));
+        assert_eq!(
+            color([rule("color: red"), rule("color: blue")].concat()),
+            "#0000ff"
+        );
+        assert_eq!(
+            color([rule("color: blue"), rule("color: red")].concat()),
+            "#ff0000"
+        );
+        // Within one rule too
+        assert_eq!(color(rule("color: red; color: blue")), "#0000ff");
+        let more_specific = declared("color: red", Origin::Author, Specificity(0, 1, 1));
+        assert_eq!(
+            color([more_specific, rule("color: blue")].concat()),
+            "#ff0000"
+        );
+    }
+
+    #[test]
+    fn css_wide_keywords() {
+        let author = |css| declared(css, Origin::Author, Specificity::default());
+        let parent = cascade(
+            author("color: red; margin-top: 10px; font-size: 20px; display: block"),
+            None,
+            None,
+        );
+        let style = |css| cascade(author(css), Some(&parent), None);
+
+        // Inherited properties inherit unless told otherwise, others don't
+        let unstyled = style("");
+        assert_eq!(unstyled.color.to_string(), "#ff0000");
+        assert_eq!(unstyled.margin.top.to_string(), "0px");
+        assert!(matches!(unstyled.display, Display::Inline));
+
+        let inherited = style("margin-top: inherit; display: inherit; color: blue; color: inherit");
+        assert_eq!(inherited.margin.top.to_string(), "10px");
+        assert!(matches!(inherited.display, Display::Block));
+        assert_eq!(inherited.color.to_string(), "#ff0000");
+
+        let initial = style("color: initial; font-size: initial");
+        assert_eq!(initial.color.to_string(), "#000000");
+        assert_eq!(initial.font.size.to_string(), "16px");
+
+        let unset = style("color: blue; color: unset; margin-top: 5px; margin-top: unset");
+        assert_eq!(unset.color.to_string(), "#ff0000");
+        assert_eq!(unset.margin.top.to_string(), "0px");
+    }
+
+    #[test]
+    fn relative_lengths_become_pixels() {
+        let author = |css| declared(css, Origin::Author, Specificity::default());
+        let root = cascade(author("font-size: 2rem; margin-top: 1rem"), None, None);
+        assert_eq!(root.font.size.to_string(), "32px");
+        assert_eq!(root.margin.top.to_string(), "32px");
+
+        let parent = cascade(author("font-size: 10px"), Some(&root), Some(&root));
+        let child = cascade(
+            author("font-size: 1.5em; margin-top: 2em; margin-left: 0.5rem"),
+            Some(&parent),
+            Some(&root),
+        );
+        assert_eq!(child.font.size.to_string(), "15px");
+        assert_eq!(child.margin.top.to_string(), "30px");
+        assert_eq!(child.margin.left.to_string(), "16px");
+        let child = cascade(author("font-size: 2rem"), Some(&parent), Some(&root));
+        assert_eq!(child.font.size.to_string(), "64px");
+    }
+}
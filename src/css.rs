@@ -0,0 +1,664 @@
+use pest::{iterators::Pair, Parser};
+use pest_derive::Parser;
+
+use crate::{
+    dom::{NodeId, DOM},
+    properties::PropertyDeclaration,
+    selector::{Selector, SelectorList, Specificity},
+};
+
+#[derive(Parser)]
+#[grammar = "css.pest"]
+pub struct CSSParser;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    Ident(String),
+    /// A name directly followed by `(`, like `rgb(`
+    Function(String),
+    AtKeyword(String),
+    Hash(String),
+    String(String),
+    /// A string cut off by the end of the line
+    BadString,
+    Number(f32),
+    Percentage(f32),
+    Dimension(f32, String),
+    Whitespace,
+    /// `<!--`, allowed around rules for pages that hide styles from old browsers
+    Cdo,
+    /// `-->`
+    Cdc,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenBrace,
+    CloseBrace,
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+    Delim(char),
+}
+
+/// Splits a style sheet into tokens, each with the text it came from.
+/// Comments are dropped.
+pub(crate) fn tokenize(input: &str) -> Vec<(Token, &str)> {
+    let mut tokens = vec![];
+    let mut position = 0;
+    while position < input.len() {
+        let token = CSSParser::parse(Rule::token, &input[position..])
+            .expect("`delim` matches any character")
+            .next()
+            .unwrap()
+            .into_inner()
+            .next()
+            .unwrap();
+        let text = &input[position..position + token.as_str().len()];
+        position += text.len();
+        if let Some(token) = parse_token(token) {
+            tokens.push((token, text));
+        }
+    }
+    tokens
+}
+
+fn parse_token(pair: Pair<Rule>) -> Option<Token> {
+    let inner_str = |pair: Pair<Rule>| unescape(pair.into_inner().next().unwrap().as_str());
+    let number = |pair: Pair<Rule>| pair.as_str().parse().unwrap_or(0.0);
+    let token = match pair.as_rule() {
+        Rule::whitespace => Token::Whitespace,
+        Rule::comment => return None,
+        Rule::cdo => Token::Cdo,
+        Rule::cdc => Token::Cdc,
+        Rule::string => Token::String(inner_str(pair)),
+        Rule::badString => Token::BadString,
+        Rule::hash => Token::Hash(inner_str(pair)),
+        Rule::atKeyword => Token::AtKeyword(inner_str(pair)),
+        Rule::dimension => {
+            let mut inner = pair.into_inner();
+            let value = number(inner.next().unwrap());
+            Token::Dimension(value, unescape(inner.next().unwrap().as_str()))
+        }
+        Rule::percentage => Token::Percentage(number(pair.into_inner().next().unwrap())),
+        Rule::number => Token::Number(number(pair)),
+        Rule::function => Token::Function(inner_str(pair)),
+        Rule::ident => Token::Ident(unescape(pair.as_str())),
+        Rule::colon => Token::Colon,
+        Rule::semicolon => Token::Semicolon,
+        Rule::comma => Token::Comma,
+        Rule::openBrace => Token::OpenBrace,
+        Rule::closeBrace => Token::CloseBrace,
+        Rule::openParen => Token::OpenParen,
+        Rule::closeParen => Token::CloseParen,
+        Rule::openBracket => Token::OpenBracket,
+        Rule::closeBracket => Token::CloseBracket,
+        Rule::delim => Token::Delim(pair.as_str().chars().next().unwrap()),
+        rule => unreachable!("{:?} is not a token", rule),
+    };
+    Some(token)
+}
+
+/// Decodes backslash escapes, like `\26` or `\"`. Escaped newlines in
+/// strings are line continuations and disappear.
+fn unescape(text: &str) -> String {
+    if !text.contains('\\') {
+        return text.to_string();
+    }
+    let mut output = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        let mut hex = String::new();
+        while hex.len() < 6 && chars.peek().is_some_and(char::is_ascii_hexdigit) {
+            hex.extend(chars.next());
+        }
+        if hex.is_empty() {
+            match chars.next() {
+                Some('\r') => {
+                    chars.next_if_eq(&'\n');
+                }
+                Some('\n' | '\x0C') | None => {}
+                Some(c) => output.push(c),
+            }
+            continue;
+        }
+        // One whitespace after a hex escape ends it
+        chars.next_if(|c| matches!(c, ' ' | '\t' | '\n'));
+        let code_point = u32::from_str_radix(&hex, 16).unwrap_or(0);
+        output.push(match char::from_u32(code_point) {
+            Some(c) if c != '\0' => c,
+            _ => char::REPLACEMENT_CHARACTER,
+        });
+    }
+    output
+}
+
+/// A parsed style sheet. Rules that couldn't be parsed were dropped like
+/// browsers do, so one mistake doesn't lose the rest of the sheet.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Stylesheet {
+    pub rules: Vec<CSSRule>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum CSSRule {
+    Style(StyleRule),
+    Media(MediaRule),
+}
+
+/// `selectors { declarations }`
+#[derive(Debug, Clone)]
+pub(crate) struct StyleRule {
+    pub selectors: SelectorList,
+    pub declarations: Vec<Declaration>,
+}
+
+/// `@media queries { rules }`
+#[derive(Debug, Clone)]
+pub(crate) struct MediaRule {
+    pub queries: MediaQueryList,
+    pub rules: Vec<CSSRule>,
+}
+
+/// A property and its value. Shorthands like `margin` are expanded into a
+/// declaration per longhand when parsing.
+#[derive(Debug, Clone)]
+pub(crate) struct Declaration {
+    pub property: PropertyDeclaration,
+    pub important: bool,
+}
+
+impl Stylesheet {
+    pub(crate) fn parse(input: &str) -> Self {
+        let tokens = tokenize(input);
+        let mut stream = TokenStream::new(&tokens);
+        Self {
+            rules: parse_rules(&mut stream, true),
+        }
+    }
+
+    /// The declarations of the style rules matching a node, in source order
+    /// and with the specificity of the selector that matched. `@media`
+    /// rules are evaluated for a viewport `viewport_width` pixels wide.
+    pub(crate) fn matching_declarations<'a>(
+        &'a self,
+        dom: &DOM,
+        id: NodeId,
+        viewport_width: f32,
+        declarations: &mut Vec<(&'a Declaration, Specificity)>,
+    ) {
+        collect_declarations(&self.rules, dom, id, viewport_width, declarations);
+    }
+
+    /// The selectors of every style rule, including those in `@media` rules.
+    pub(crate) fn selectors(&self) -> Vec<&Selector> {
+        fn collect<'a>(rules: &'a [CSSRule], selectors: &mut Vec<&'a Selector>) {
+            for rule in rules {
+                match rule {
+                    CSSRule::Style(rule) => selectors.extend(&rule.selectors.0),
+                    CSSRule::Media(rule) => collect(&rule.rules, selectors),
+                }
+            }
+        }
+        let mut selectors = vec![];
+        collect(&self.rules, &mut selectors);
+        selectors
+    }
+}
+
+fn collect_declarations<'a>(
+    rules: &'a [CSSRule],
+    dom: &DOM,
+    id: NodeId,
+    viewport_width: f32,
+    declarations: &mut Vec<(&'a Declaration, Specificity)>,
+) {
+    for rule in rules {
+        match rule {
+            CSSRule::Style(rule) => {
+                if let Some(specificity) = rule.selectors.matching_specificity(dom, id) {
+                    declarations.extend(
+                        rule.declarations
+                            .iter()
+                            .map(|declaration| (declaration, specificity)),
+                    );
+                }
+            }
+            CSSRule::Media(rule) if rule.queries.matches(viewport_width) => {
+                collect_declarations(&rule.rules, dom, id, viewport_width, declarations);
+            }
+            CSSRule::Media(_) => {}
+        }
+    }
+}
+
+/// Parses the `style` attribute of an element, a list of declarations
+/// without braces around them.
+pub(crate) fn parse_style_attribute(input: &str) -> Vec<Declaration> {
+    parse_declarations(&tokenize(input))
+}
+
+/// A cursor over tokens that can skip whole blocks.
+struct TokenStream<'t, 'a> {
+    tokens: &'t [(Token, &'a str)],
+    position: usize,
+}
+
+impl<'t, 'a> TokenStream<'t, 'a> {
+    fn new(tokens: &'t [(Token, &'a str)]) -> Self {
+        Self {
+            tokens,
+            position: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<&'t Token> {
+        let token = self.peek()?;
+        self.position += 1;
+        Some(token)
+    }
+
+    /// Skips a token, or a whole `{}`, `()`, `[]` block or function up to
+    /// its matching end.
+    fn skip_component(&mut self) {
+        let close = match self.next() {
+            Some(Token::OpenBrace) => Token::CloseBrace,
+            Some(Token::OpenParen | Token::Function(_)) => Token::CloseParen,
+            Some(Token::OpenBracket) => Token::CloseBracket,
+            _ => return,
+        };
+        while let Some(token) = self.peek() {
+            if *token == close {
+                self.position += 1;
+                return;
+            }
+            self.skip_component();
+        }
+    }
+
+    /// Skips components until one of `stops` or the end, which is left to be
+    /// read next. Returns the skipped tokens.
+    fn skip_until(&mut self, stops: &[Token]) -> &'t [(Token, &'a str)] {
+        let start = self.position;
+        while self.peek().is_some_and(|token| !stops.contains(token)) {
+            self.skip_component();
+        }
+        &self.tokens[start..self.position]
+    }
+}
+
+/// Parses rules up to the end, or up to the `}` closing the block they're
+/// in unless they're at the top level.
+fn parse_rules(stream: &mut TokenStream, top_level: bool) -> Vec<CSSRule> {
+    let mut rules = vec![];
+    while let Some(token) = stream.peek() {
+        match token {
+            Token::Whitespace => {
+                stream.next();
+            }
+            Token::Cdo | Token::Cdc if top_level => {
+                stream.next();
+            }
+            Token::CloseBrace if !top_level => break,
+            Token::AtKeyword(_) => rules.extend(parse_at_rule(stream, top_level)),
+            _ => rules.extend(parse_style_rule(stream, top_level)),
+        }
+    }
+    rules
+}
+
+fn parse_at_rule(stream: &mut TokenStream, top_level: bool) -> Option<CSSRule> {
+    let Some(Token::AtKeyword(name)) = stream.next() else {
+        unreachable!("at-rules start with an at-keyword");
+    };
+    let stops: &[Token] = if top_level {
+        &[Token::Semicolon, Token::OpenBrace]
+    } else {
+        &[Token::Semicolon, Token::OpenBrace, Token::CloseBrace]
+    };
+    let prelude = stream.skip_until(stops);
+    match stream.peek() {
+        Some(Token::OpenBrace) if name.eq_ignore_ascii_case("media") => {
+            stream.next();
+            let rules = parse_rules(stream, false);
+            stream.next();
+            Some(CSSRule::Media(MediaRule {
+                queries: MediaQueryList::parse(prelude),
+                rules,
+            }))
+        }
+        // Other at-rules, like `@import` and `@font-face`, aren't supported
+        Some(Token::OpenBrace) => {
+            stream.skip_component();
+            None
+        }
+        Some(Token::Semicolon) => {
+            stream.next();
+            None
+        }
+        _ => None,
+    }
+}
+
+fn parse_style_rule(stream: &mut TokenStream, top_level: bool) -> Option<CSSRule> {
+    let stops: &[Token] = if top_level {
+        &[Token::OpenBrace]
+    } else {
+        &[Token::OpenBrace, Token::CloseBrace]
+    };
+    let prelude = stream.skip_until(stops);
+    // A rule cut off before its block is dropped
+    if stream.peek() != Some(&Token::OpenBrace) {
+        return None;
+    }
+    stream.next();
+    let block = stream.skip_until(&[Token::CloseBrace]);
+    stream.next();
+
+    // Comments are gone from the tokens, so the selector is put back
+    // together from them
+    let selector: String = prelude
+        .iter()
+        .map(|(token, text)| match token {
+            Token::Whitespace => " ",
+            _ => text,
+        })
+        .collect();
+    // A rule with an invalid selector is dropped entirely
+    let selectors = SelectorList::parse(selector.trim()).ok()?;
+    Some(CSSRule::Style(StyleRule {
+        selectors,
+        declarations: parse_declarations(block),
+    }))
+}
+
+/// Parses `name: value` pairs separated by `;`. Invalid declarations are
+/// skipped up to the next `;`.
+fn parse_declarations(tokens: &[(Token, &str)]) -> Vec<Declaration> {
+    let mut stream = TokenStream::new(tokens);
+    let mut declarations = vec![];
+    while let Some(token) = stream.peek() {
+        match token {
+            Token::Whitespace | Token::Semicolon => {
+                stream.next();
+            }
+            _ => {
+                let declaration = stream.skip_until(&[Token::Semicolon]);
+                declarations.extend(parse_declaration(declaration));
+            }
+        }
+    }
+    declarations
+}
+
+fn parse_declaration(tokens: &[(Token, &str)]) -> Vec<Declaration> {
+    let mut tokens = tokens
+        .iter()
+        .map(|(token, _)| token)
+        .filter(|token| **token != Token::Whitespace);
+    let (Some(Token::Ident(name)), Some(Token::Colon)) = (tokens.next(), tokens.next()) else {
+        return vec![];
+    };
+    let mut value: Vec<Token> = tokens.cloned().collect();
+    let important = matches!(
+        value.as_slice(),
+        [.., Token::Delim('!'), Token::Ident(important)] if important.eq_ignore_ascii_case("important")
+    );
+    if important {
+        value.truncate(value.len() - 2);
+    }
+    if value.contains(&Token::BadString) {
+        return vec![];
+    }
+    PropertyDeclaration::parse(&name.to_ascii_lowercase(), &value)
+        .into_iter()
+        .flatten()
+        .map(|property| Declaration {
+            property,
+            important,
+        })
+        .collect()
+}
+
+/// The comma separated queries of an `@media` rule, matching if any does.
+/// Only media types and width features are supported, other queries never
+/// match.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MediaQueryList(Vec<MediaQuery>);
+
+#[derive(Debug, Clone)]
+pub(crate) struct MediaQuery {
+    /// `not screen`
+    negated: bool,
+    /// Lowercased, like `screen` or `print`. `None` matches all media.
+    media_type: Option<String>,
+    /// Joined with `and`
+    features: Vec<MediaFeature>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum MediaFeature {
+    /// `(min-width: 600px)`, in pixels
+    MinWidth(f32),
+    MaxWidth(f32),
+    Width(f32),
+    /// A feature Kale can't evaluate, which never matches
+    Unknown,
+}
+
+impl MediaQueryList {
+    fn parse(tokens: &[(Token, &str)]) -> Self {
+        let tokens: Vec<&Token> = tokens
+            .iter()
+            .map(|(token, _)| token)
+            .filter(|token| **token != Token::Whitespace)
+            .collect();
+        if tokens.is_empty() {
+            return Self(vec![]);
+        }
+        Self(
+            tokens
+                .split(|token| **token == Token::Comma)
+                .map(MediaQuery::parse)
+                .collect(),
+        )
+    }
+
+    pub(crate) fn matches(&self, viewport_width: f32) -> bool {
+        self.0.is_empty() || self.0.iter().any(|query| query.matches(viewport_width))
+    }
+}
+
+impl MediaQuery {
+    /// A query that can't be parsed becomes `not all`, which never matches.
+    fn parse(tokens: &[&Token]) -> Self {
+        let never = Self {
+            negated: true,
+            media_type: None,
+            features: vec![],
+        };
+        let keyword = |index: usize, keyword: &str| matches!(tokens.get(index), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword));
+
+        let mut index = 0;
+        let negated = keyword(0, "not");
+        if negated || keyword(0, "only") {
+            index += 1;
+        }
+        let media_type = match tokens.get(index) {
+            Some(Token::Ident(media_type)) => {
+                index += 1;
+                Some(media_type.to_ascii_lowercase())
+            }
+            _ if negated => return never,
+            _ => None,
+        };
+
+        let mut features = vec![];
+        while index < tokens.len() {
+            // Features after a media type are joined with `and`
+            if media_type.is_some() || !features.is_empty() {
+                if !keyword(index, "and") {
+                    return never;
+                }
+                index += 1;
+            }
+            let Some(length) = tokens[index..]
+                .iter()
+                .position(|token| **token == Token::CloseParen)
+            else {
+                return never;
+            };
+            features.push(MediaFeature::parse(&tokens[index..=index + length]));
+            index += length + 1;
+        }
+        if media_type.is_none() && features.is_empty() {
+            return never;
+        }
+        Self {
+            negated,
+            media_type,
+            features,
+        }
+    }
+
+    fn matches(&self, viewport_width: f32) -> bool {
+        // Kale only ever renders to a screen
+        let media_type = matches!(self.media_type.as_deref(), None | Some("all" | "screen"));
+        let features = self.features.iter().all(|feature| match *feature {
+            MediaFeature::MinWidth(width) => viewport_width >= width,
+            MediaFeature::MaxWidth(width) => viewport_width <= width,
+            MediaFeature::Width(width) => viewport_width == width,
+            MediaFeature::Unknown => false,
+        });
+        (media_type && features) != self.negated
+    }
+}
+
+impl MediaFeature {
+    /// Parses `( name : length )`.
+    fn parse(tokens: &[&Token]) -> Self {
+        let [Token::OpenParen, Token::Ident(name), Token::Colon, value, Token::CloseParen] = tokens
+        else {
+            return Self::Unknown;
+        };
+        let pixels = match value {
+            Token::Number(number) if *number == 0.0 => 0.0,
+            Token::Dimension(number, unit) if unit.eq_ignore_ascii_case("px") => *number,
+            // Relative to the initial font size, not the root element's
+            Token::Dimension(number, unit)
+                if unit.eq_ignore_ascii_case("em") || unit.eq_ignore_ascii_case("rem") =>
+            {
+                number * 16.0
+            }
+            _ => return Self::Unknown,
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "min-width" => Self::MinWidth(pixels),
+            "max-width" => Self::MaxWidth(pixels),
+            "width" => Self::Width(pixels),
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::styling::{Display, FontWeight};
+
+    fn style_rules(stylesheet: &Stylesheet) -> Vec<&StyleRule> {
+        stylesheet
+            .rules
+            .iter()
+            .filter_map(|rule| match rule {
+                CSSRule::Style(rule) => Some(rule),
+                CSSRule::Media(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn malformed_declarations_are_skipped() {
+        let declarations = parse_style_attribute(
+            "color: ; margin-top 1px; : bold; 12px; color: nonsense; \
+             font-weight: bold !important; display: block ! IMPORTANT; margin-left: 1px 2px",
+        );
+        assert!(matches!(
+            declarations.as_slice(),
+            [
+                Declaration {
+                    property: PropertyDeclaration::FontWeight(FontWeight::Bold),
+                    important: true,
+                },
+                Declaration {
+                    property: PropertyDeclaration::Display(Display::Block),
+                    important: true,
+                },
+            ]
+        ));
+
+        // A string cut off by a newline only takes its own declaration along
+        let declarations = parse_style_attribute("font-family: \"Times\n; display: block");
+        assert!(matches!(
+            declarations.as_slice(),
+            [Declaration {
+                property: PropertyDeclaration::Display(Display::Block),
+                important: false,
+            }]
+        ));
+        // Blocks are skipped whole, `;` inside them included
+        let declarations = parse_style_attribute("color: {red; blue}; display: block");
+        assert_eq!(declarations.len(), 1);
+    }
+
+    #[test]
+    fn malformed_rules_are_dropped() {
+        let stylesheet = Stylesheet::parse(
+            "<!-- p { display: block } --> \
+             p:unknown, a { display: block } \
+             } a { display: block } \
+             div { display: block } \
+             span { display: block",
+        );
+        let rules = style_rules(&stylesheet);
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].selectors, SelectorList::parse("p").unwrap());
+        assert_eq!(rules[1].selectors, SelectorList::parse("div").unwrap());
+        // The rule cut off by the end of the sheet still applies
+        assert_eq!(rules[2].selectors, SelectorList::parse("span").unwrap());
+        assert_eq!(rules[2].declarations.len(), 1);
+    }
+
+    #[test]
+    fn unsupported_at_rules_are_skipped() {
+        let stylesheet = Stylesheet::parse(
+            "@import url(\"x.css\"); \
+             @font-face { font-family: x; src: url(x.woff) } \
+             @unknown foo { p { display: block } } \
+             @media screen { @page { margin: 0 } p { display: block } } \
+             @charset \"utf-8\"; \
+             a { display: block }",
+        );
+        assert!(matches!(
+            stylesheet.rules.as_slice(),
+            [CSSRule::Media(media), CSSRule::Style(_)] if media.rules.len() == 1
+        ));
+        let rules = style_rules(&stylesheet);
+        assert_eq!(rules[0].selectors, SelectorList::parse("a").unwrap());
+
+        // An at-rule inside a block ends at its `}`
+        let stylesheet = Stylesheet::parse("@media screen { p { display: block } @oops } a { }");
+        assert!(matches!(
+            stylesheet.rules.as_slice(),
+            [CSSRule::Media(media), CSSRule::Style(_)] if media.rules.len() == 1
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+use crate::html::Span;
+
+/// Something the parser had to recover from. Parsing never fails, these only
+/// describe how the document deviates from well-formed HTML.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseDiagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DiagnosticKind {
+    /// An element that requires an end tag was closed implicitly
+    MissingCloseTag { tag: String },
+    /// An end tag closed an element while other elements were still open inside it
+    MismatchedEndTag { expected: String, found: String },
+    /// An end tag that doesn't match any open element
+    StrayEndTag { tag: String },
+    /// A `&name;` reference that isn't in the named character reference table
+    UnknownEntity { name: String },
+    /// A `<` that doesn't start a tag, which should be written `&lt;`
+    UnescapedLessThan,
+}
+
+impl ParseDiagnostic {
+    pub(crate) fn new(kind: DiagnosticKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl std::fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.span)?;
+        match &self.kind {
+            DiagnosticKind::MissingCloseTag { tag } => write!(f, "missing close tag for <{}>", tag),
+            DiagnosticKind::MismatchedEndTag { expected, found } => {
+                write!(f, "expected </{}> but found </{}>", expected, found)
+            }
+            DiagnosticKind::StrayEndTag { tag } => write!(f, "stray end tag </{}>", tag),
+            DiagnosticKind::UnknownEntity { name } => {
+                write!(f, "unknown character reference &{};", name)
+            }
+            DiagnosticKind::UnescapedLessThan => write!(f, "unescaped '<' in text"),
+        }
+    }
+}
@@ -1,24 +1,93 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::OnceLock,
+};
+
+use serde::Serialize;
+
 use crate::{
+    cascade::{self, CascadedDeclaration, Origin},
+    css::{self, Stylesheet},
+    events::EventListener,
     html::HTMLElement,
-    styling::{
-        Color, Display, Font, FontFamily, FontStyle, FontWeight, Margin, Style, TextDecoration,
-        TextDecorationLine, TextDecorationStyle, Unit,
-    },
+    metadata::DocumentMetadata,
+    selector::{HoverDependence, SelectorError, SelectorList, Specificity},
+    styling::Style,
+    traversal::Visitor,
 };
 
-use uuid::Uuid;
+/// Index of a node in a `DOM`'s arena. `DOM::construct_dom` allocates nodes in
+/// document order, so the same page always gets the same ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct NodeId(usize);
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A node of the `DOM` arena along with links to its relatives.
+#[derive(Debug, Clone)]
+pub(crate) struct DOMNode {
+    pub element: DOMElement,
+    pub parent: Option<NodeId>,
+    pub first_child: Option<NodeId>,
+    pub last_child: Option<NodeId>,
+    pub previous_sibling: Option<NodeId>,
+    pub next_sibling: Option<NodeId>,
+    pub dirty: Dirty,
+    pub state: ElementState,
+}
+
+/// Dynamic state that pseudo-classes like `:hover` select on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ElementState {
+    /// Under the pointer, or an ancestor of what is
+    pub hovered: bool,
+    /// A link to a visited URL, see `DOM::update_visited`
+    pub visited: bool,
+}
+
+/// What has to be recomputed for a node after the `DOM` was mutated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Dirty {
+    /// Style and actions, see `DOM::restyle`
+    pub style: bool,
+    /// The boxes of the node and its descendants
+    pub layout: bool,
+}
+
+/// Why a mutation of the `DOM` was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MutationError {
+    /// The node would end up inside itself or inside a text node
+    HierarchyRequest,
+    /// The node isn't a child of the given parent
+    NotFound,
+}
+
+impl std::fmt::Display for MutationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MutationError::HierarchyRequest => write!(f, "node can't be inserted there"),
+            MutationError::NotFound => write!(f, "node is not a child of the parent"),
+        }
+    }
+}
+
+impl std::error::Error for MutationError {}
 
 #[derive(Debug, Clone)]
 pub(crate) enum DOMElement {
     View {
-        id: String,
         tag: String,
+        /// Attributes by lowercase name
+        attributes: BTreeMap<String, String>,
         style: Style,
-        children: Vec<DOMElement>,
         actions: Vec<DOMAction>,
     },
     Text {
-        id: String,
         style: Style,
         text: String,
         actions: Vec<DOMAction>,
@@ -39,505 +108,1140 @@ impl DOMElement {
         }
     }
 
-    pub(crate) fn id(&self) -> &str {
+    pub(crate) fn tag(&self) -> &str {
         match self {
-            Self::View { id, .. } => id,
-            Self::Text { id, .. } => id,
+            Self::View { tag, .. } => tag,
+            Self::Text { .. } => "text",
         }
     }
 
-    pub(crate) fn tag(&self) -> &str {
+    pub(crate) fn get_attribute(&self, name: &str) -> Option<&str> {
         match self {
-            Self::View { tag, .. } => tag,
-            Self::Text { .. } => "text",
+            Self::View { attributes, .. } => attributes
+                .get(&name.to_ascii_lowercase())
+                .map(String::as_str),
+            Self::Text { .. } => None,
         }
     }
+
+    pub(crate) fn has_attribute(&self, name: &str) -> bool {
+        self.get_attribute(name).is_some()
+    }
+
+    /// The whitespace separated names in the `class` attribute.
+    pub(crate) fn class_list(&self) -> Vec<&str> {
+        self.get_attribute("class")
+            .map(|class| class.split_ascii_whitespace().collect())
+            .unwrap_or_default()
+    }
 }
 
 impl DOMElement {
-    pub(crate) fn set_style(&mut self, style: Style) {
-        match self {
-            Self::View { style: s, .. } => *s = style,
-            Self::Text { style: s, .. } => *s = style,
+    /// Sets an attribute on an element, text nodes have none.
+    pub(crate) fn set_attribute(&mut self, name: &str, value: &str) {
+        if let Self::View { attributes, .. } = self {
+            attributes.insert(name.to_ascii_lowercase(), value.to_string());
         }
     }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DOMAction {
+    ClickToRedirect(String),
+    /// `href="#section"`, scrolls to the element with that `id` or `<a name>`
+    ScrollToFragment(String),
+    /// A scheme Kale can't load, like `mailto:`
+    ExternalLink(String),
+    /// `target="_blank"`
+    OpenInNewWindow(String),
+    /// `download`, saves the target instead of showing it
+    Download {
+        url: String,
+        /// The name the `download` attribute suggests
+        file_name: Option<String>,
+    },
+}
 
-    pub(crate) fn get(&self, id: &str) -> Option<&DOMElement> {
-        if self.id() == id {
-            return Some(self);
+impl DOMAction {
+    pub(crate) fn from_html_element(tag: &str, attributes: &BTreeMap<String, String>) -> Vec<Self> {
+        if !is_link(tag, attributes) {
+            return vec![];
         }
+        let href = attributes["href"].trim().to_string();
+        let action = if let Some(fragment) = href.strip_prefix('#') {
+            DOMAction::ScrollToFragment(fragment.to_string())
+        } else if !matches!(scheme(&href).as_deref(), None | Some("http" | "https")) {
+            DOMAction::ExternalLink(href)
+        } else if let Some(file_name) = attributes.get("download") {
+            DOMAction::Download {
+                url: href,
+                file_name: Some(file_name.trim().to_string()).filter(|name| !name.is_empty()),
+            }
+        } else if attributes
+            .get("target")
+            .is_some_and(|target| target.eq_ignore_ascii_case("_blank"))
+        {
+            DOMAction::OpenInNewWindow(href)
+        } else {
+            DOMAction::ClickToRedirect(href)
+        };
+        vec![action]
+    }
+}
+
+/// The lowercased scheme of an absolute URL, like `mailto` in `mailto:me@example.com`.
+fn scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once(':')?;
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then(|| scheme.to_ascii_lowercase())
+}
+
+/// Elements that `:link` and `:visited` apply to.
+pub(crate) fn is_link(tag: &str, attributes: &BTreeMap<String, String>) -> bool {
+    matches!(tag, "a" | "area") && attributes.contains_key("href")
+}
+
+impl DOMElement {
+    /// Sets the style computed by the cascade, and the actions from the tag
+    /// and attributes and what the parent passes down.
+    fn resolve(&mut self, computed_style: Style, inherited_actions: &[DOMAction]) {
         match self {
-            Self::View { children, .. } => {
-                for child in children {
-                    if let Some(e) = child.get(id) {
-                        return Some(e);
-                    }
-                }
+            DOMElement::View {
+                tag,
+                attributes,
+                style,
+                actions,
+            } => {
+                *style = computed_style;
+                *actions = inherited_actions.to_vec();
+                actions.extend(DOMAction::from_html_element(tag, attributes));
             }
-            Self::Text { id: self_id, .. } => {
-                if self_id == id {
-                    return Some(self);
-                }
+            DOMElement::Text { style, actions, .. } => {
+                *style = computed_style;
+                *actions = inherited_actions.to_vec();
             }
         }
-        None
     }
+}
 
-    pub(crate) fn get_mut(&mut self, id: &str) -> Option<&mut DOMElement> {
-        if self.id() == id {
-            return Some(self);
+impl HTMLElement {
+    /// Adds this element and its children to `dom` under `parent`, to be
+    /// styled by the next `DOM::restyle`.
+    pub(crate) fn into_dom_element(self, dom: &mut DOM, parent: Option<NodeId>) -> NodeId {
+        let (element, children) = match self {
+            HTMLElement::Element {
+                name,
+                attributes,
+                children,
+                ..
+            } => (
+                DOMElement::View {
+                    tag: name,
+                    // The tokenizer already lowercased names and kept the first duplicate
+                    attributes: attributes.into_iter().collect(),
+                    style: Style::default(),
+                    actions: vec![],
+                },
+                children,
+            ),
+            HTMLElement::Text { text, .. } => (
+                DOMElement::Text {
+                    style: Style::default(),
+                    text,
+                    actions: vec![],
+                },
+                vec![],
+            ),
+            HTMLElement::Comment { .. } | HTMLElement::Doctype { .. } => {
+                unreachable!("hidden nodes aren't added to the DOM")
+            }
+        };
+        // Add the node before its children so ids follow document order
+        let id = dom.append(parent, element);
+        // Recurse on children
+        for child in children
+            .into_iter()
+            .filter(|child| !child.is_header() && !child.is_hidden())
+        {
+            child.into_dom_element(dom, Some(id));
         }
-        match self {
-            Self::View { children, .. } => {
-                for child in children {
-                    if let Some(e) = child.get_mut(id) {
-                        return Some(e);
+        id
+    }
+}
+
+/// The document as an arena of nodes. Nodes are addressed by `NodeId` and
+/// link to their parent, children and siblings.
+#[derive(Debug, Default)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct DOM {
+    nodes: Vec<DOMNode>,
+    /// Nodes without a parent, in document order
+    roots: Vec<NodeId>,
+    /// What the `<head>` said, which isn't part of the rendered nodes
+    pub metadata: DocumentMetadata,
+    /// The node under the pointer
+    hovered: Option<NodeId>,
+    /// Registered with `add_event_listener`
+    pub(crate) listeners: HashMap<NodeId, Vec<EventListener>>,
+    /// Where key events go, see `focus`
+    pub(crate) focused: Option<NodeId>,
+    /// The reader's, see `set_user_stylesheet`
+    user_stylesheet: Option<Stylesheet>,
+    /// Parsed from `metadata.styles`
+    author_stylesheets: Vec<Stylesheet>,
+    /// What `@media` queries are evaluated for, see `set_viewport_width`
+    viewport_width: f32,
+}
+
+/// The width of a new window.
+const DEFAULT_VIEWPORT_WIDTH: f32 = 800.0;
+
+/// The user-agent style sheet.
+const USER_AGENT_CSS: &str = include_str!("user_agent.css");
+
+/// Kale's defaults for every element, parsed once and shared by every DOM.
+fn user_agent_stylesheet() -> &'static Stylesheet {
+    static STYLESHEET: OnceLock<Stylesheet> = OnceLock::new();
+    STYLESHEET.get_or_init(|| Stylesheet::parse(USER_AGENT_CSS))
+}
+
+impl DOM {
+    pub(crate) fn construct_dom(html_elements: Vec<HTMLElement>) -> Self {
+        let metadata = DocumentMetadata::from_html(&html_elements);
+        let mut dom = Self {
+            author_stylesheets: metadata
+                .styles
+                .iter()
+                .map(|style| Stylesheet::parse(style))
+                .collect(),
+            metadata,
+            viewport_width: DEFAULT_VIEWPORT_WIDTH,
+            ..Self::default()
+        };
+        for element in html_elements
+            .into_iter()
+            .filter(|element| !element.is_header() && !element.is_hidden())
+        {
+            element.into_dom_element(&mut dom, None);
+        }
+        // Selectors like `:last-child` can only match once the whole tree
+        // is there
+        dom.restyle();
+        dom
+    }
+
+    /// Brings the document up to date with a later parse of the same page,
+    /// like the next snapshot of a streaming parser. Parsing only ever adds
+    /// content after what is there, so existing nodes are kept along with
+    /// their state and only what is new gets styled.
+    pub(crate) fn update(&mut self, html_elements: Vec<HTMLElement>) {
+        let metadata = DocumentMetadata::from_html(&html_elements);
+        if metadata.styles != self.metadata.styles {
+            self.author_stylesheets = metadata
+                .styles
+                .iter()
+                .map(|style| Stylesheet::parse(style))
+                .collect();
+            for root in self.roots.clone() {
+                self.mark_dirty(root);
+            }
+        }
+        self.metadata = metadata;
+        let roots = self.roots.clone();
+        self.update_children(None, &roots, html_elements);
+        self.restyle();
+    }
+
+    /// Matches `html_elements` up with the `existing` children of `parent`.
+    /// All but the last existing child were closed by the parse they came
+    /// from, so only the last one can have grown.
+    fn update_children(
+        &mut self,
+        parent: Option<NodeId>,
+        existing: &[NodeId],
+        html_elements: Vec<HTMLElement>,
+    ) {
+        let mut html_elements = html_elements
+            .into_iter()
+            .filter(|element| !element.is_header() && !element.is_hidden())
+            .skip(existing.len().saturating_sub(1));
+        if let Some(&last) = existing.last() {
+            match (html_elements.next(), &self.node(last).element) {
+                (Some(HTMLElement::Text { text, .. }), DOMElement::Text { text: old, .. }) => {
+                    if text != *old {
+                        self.set_text(last, &text);
                     }
                 }
-            }
-            Self::Text { id: self_id, .. } => {
-                if self_id == id {
-                    return Some(self);
+                (
+                    Some(HTMLElement::Element { name, children, .. }),
+                    DOMElement::View { tag, .. },
+                ) if name == *tag => {
+                    let existing: Vec<_> = self.children(last).collect();
+                    self.update_children(Some(last), &existing, children);
+                }
+                (element, _) => {
+                    // Not the same node after all, so start over from it
+                    self.unlink(last);
+                    if let Some(element) = element {
+                        element.into_dom_element(self, parent);
+                    }
                 }
             }
         }
-        None
+        let mut html_elements = html_elements.peekable();
+        if let (Some(&last), Some(_)) = (existing.last(), html_elements.peek()) {
+            // It isn't the `:last-child` anymore
+            if self.parent(last) == parent {
+                self.mark_dirty(last);
+            }
+        }
+        for element in html_elements {
+            element.into_dom_element(self, parent);
+        }
     }
 
-    pub(crate) fn set_clicked(&mut self, id: &str) {
-        match self {
-            Self::View {
-                children,
-                id: id_,
-                style,
-                ..
-            } => {
-                if id == id_ {
-                    let style = style.clone();
-                    self.set_style(Style {
-                        color: Color::new(255, 0, 0),
-                        ..style
-                    });
-                } else {
-                    for child in children {
-                        child.set_hovered(id);
-                    }
+    /// Adds a node as the last child of `parent`, or as the last root.
+    fn append(&mut self, parent: Option<NodeId>, element: DOMElement) -> NodeId {
+        let id = self.create(element);
+        self.link(parent, id, None);
+        id
+    }
+
+    /// Adds a node to the arena without attaching it anywhere. It is styled
+    /// by the next `restyle` once attached.
+    fn create(&mut self, element: DOMElement) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(DOMNode {
+            element,
+            parent: None,
+            first_child: None,
+            last_child: None,
+            previous_sibling: None,
+            next_sibling: None,
+            dirty: Dirty {
+                style: true,
+                layout: true,
+            },
+            state: ElementState::default(),
+        });
+        id
+    }
+
+    /// Attaches a detached node under `parent`, or among the roots, before
+    /// `reference` or last.
+    fn link(&mut self, parent: Option<NodeId>, id: NodeId, reference: Option<NodeId>) {
+        let previous_sibling = match (reference, parent) {
+            (Some(reference), _) => self.node(reference).previous_sibling,
+            (None, Some(parent)) => self.node(parent).last_child,
+            (None, None) => self.roots.last().copied(),
+        };
+        let node = &mut self.nodes[id.0];
+        node.parent = parent;
+        node.previous_sibling = previous_sibling;
+        node.next_sibling = reference;
+
+        match previous_sibling {
+            Some(previous_sibling) => self.nodes[previous_sibling.0].next_sibling = Some(id),
+            None => {
+                if let Some(parent) = parent {
+                    self.nodes[parent.0].first_child = Some(id);
                 }
             }
-            Self::Text { id: self_id, .. } => {
-                if self_id == id {
-                    self.set_style(Style {
-                        color: Color::new(255, 0, 0),
-                        ..self.style().clone()
-                    });
+        }
+        match reference {
+            Some(reference) => self.nodes[reference.0].previous_sibling = Some(id),
+            None => {
+                if let Some(parent) = parent {
+                    self.nodes[parent.0].last_child = Some(id);
                 }
             }
         }
+        if parent.is_none() {
+            let index = reference
+                .and_then(|reference| self.roots.iter().position(|&root| root == reference))
+                .unwrap_or(self.roots.len());
+            self.roots.insert(index, id);
+        }
     }
 
-    pub(crate) fn set_hovered(&mut self, id: &str) {
-        match self {
-            Self::View {
-                children,
-                id: id_,
-                style,
-                ..
-            } => {
-                if id == id_ {
-                    let style = style.clone();
-                    self.set_style(Style {
-                        color: Color::new(255, 0, 0),
-                        ..style
-                    });
-                } else {
-                    for child in children {
-                        child.set_hovered(id);
-                    }
+    /// Detaches a node, along with its descendants, from its parent and
+    /// siblings. It stays in the arena and can be attached again.
+    fn unlink(&mut self, id: NodeId) {
+        let DOMNode {
+            parent,
+            previous_sibling,
+            next_sibling,
+            ..
+        } = *self.node(id);
+        match previous_sibling {
+            Some(previous_sibling) => self.nodes[previous_sibling.0].next_sibling = next_sibling,
+            None => {
+                if let Some(parent) = parent {
+                    self.nodes[parent.0].first_child = next_sibling;
                 }
             }
-            Self::Text { id: self_id, .. } => {
-                if self_id == id {
-                    self.set_style(Style {
-                        color: Color::new(255, 0, 0),
-                        ..self.style().clone()
-                    });
+        }
+        match next_sibling {
+            Some(next_sibling) => self.nodes[next_sibling.0].previous_sibling = previous_sibling,
+            None => {
+                if let Some(parent) = parent {
+                    self.nodes[parent.0].last_child = previous_sibling;
                 }
             }
         }
+        if parent.is_none() {
+            self.roots.retain(|&root| root != id);
+        }
+        let node = &mut self.nodes[id.0];
+        node.parent = None;
+        node.previous_sibling = None;
+        node.next_sibling = None;
     }
 }
 
-impl ToString for DOMElement {
-    fn to_string(&self) -> String {
-        match self {
-            DOMElement::View {
-                tag,
-                style,
-                children,
-                id,
-                ..
-            } => {
-                let mut result = String::new();
-                let attributes = format!("id=\"{}\"", id);
-                result.push_str(&format!("<{} {}>", tag, attributes));
+#[allow(dead_code, reason = "the mutation API is for embedders and scripts")]
+impl DOM {
+    /// Creates a detached element, to be inserted with `append_child` or
+    /// `insert_before`.
+    pub(crate) fn create_element(&mut self, tag: &str) -> NodeId {
+        let element = DOMElement::View {
+            tag: tag.to_ascii_lowercase(),
+            attributes: BTreeMap::new(),
+            style: Style::default(),
+            actions: vec![],
+        };
+        self.create(element)
+    }
+
+    /// Creates a detached text node.
+    pub(crate) fn create_text_node(&mut self, text: &str) -> NodeId {
+        let element = DOMElement::Text {
+            style: Style::default(),
+            text: text.to_string(),
+            actions: vec![],
+        };
+        self.create(element)
+    }
+
+    pub(crate) fn append_child(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+    ) -> Result<(), MutationError> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Inserts `child` into `parent` before `reference`, or last if there is
+    /// none. A child that is already in the document is moved.
+    pub(crate) fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<(), MutationError> {
+        let inside_child = parent == child || self.ancestors(parent).any(|id| id == child);
+        if inside_child || matches!(self.node(parent).element, DOMElement::Text { .. }) {
+            return Err(MutationError::HierarchyRequest);
+        }
+        if reference.is_some_and(|reference| self.parent(reference) != Some(parent)) {
+            return Err(MutationError::NotFound);
+        }
+        // Inserting a node before itself leaves it where it is
+        let reference = match reference {
+            Some(reference) if reference == child => self.node(child).next_sibling,
+            reference => reference,
+        };
+
+        if let Some(old_parent) = self.parent(child) {
+            self.mark_dirty(old_parent);
+        }
+        self.unlink(child);
+        self.link(Some(parent), child, reference);
+        // Siblings can match different selectors now, like `:first-child`
+        self.mark_dirty(parent);
+        Ok(())
+    }
+
+    pub(crate) fn remove_child(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+    ) -> Result<(), MutationError> {
+        if self.parent(child) != Some(parent) {
+            return Err(MutationError::NotFound);
+        }
+        self.unlink(child);
+        self.mark_dirty(parent);
+        Ok(())
+    }
+
+    /// Replaces the text of a text node, or all children of an element with
+    /// a single text node.
+    pub(crate) fn set_text(&mut self, id: NodeId, text: &str) {
+        match &mut self.nodes[id.0].element {
+            DOMElement::Text { text: old_text, .. } => {
+                *old_text = text.to_string();
+                self.mark_layout_dirty(id);
+            }
+            DOMElement::View { .. } => {
+                let children: Vec<_> = self.children(id).collect();
                 for child in children {
-                    result.push_str(&child.to_string());
+                    self.unlink(child);
                 }
-                result.push_str(&format!("</{}>", tag));
-                result
+                let text = self.create_text_node(text);
+                self.link(Some(id), text, None);
+                self.mark_dirty(id);
             }
-            DOMElement::Text { text, .. } => text.clone(),
         }
     }
-}
 
-#[derive(Debug, Clone)]
-pub(crate) enum DOMAction {
-    ClickToRedirect(String),
+    pub(crate) fn set_attribute(&mut self, id: NodeId, name: &str, value: &str) {
+        self.nodes[id.0].element.set_attribute(name, value);
+        self.mark_dirty(id);
+    }
 }
 
-impl DOMAction {
-    pub(crate) fn from_html_element(tag: &str, attributes: &Vec<(String, String)>) -> Vec<Self> {
-        match tag {
-            "a" | "A" => {
-                let mut actions = vec![];
-                for (key, value) in attributes {
-                    if key == "href" || key == "HREF" {
-                        actions.push(DOMAction::ClickToRedirect(value.clone()));
-                    }
+impl DOM {
+    /// Recomputes style and actions of the nodes mutations marked dirty.
+    pub(crate) fn restyle(&mut self) {
+        // Parents come first in document order, so they're already clean
+        let dirty: Vec<_> = self
+            .pre_order()
+            .filter(|&id| self.node(id).dirty.style)
+            .collect();
+        for id in dirty {
+            let declarations = self.cascaded_declarations(id);
+            let root = self
+                .ancestors(id)
+                .last()
+                .map(|root| self.node(root).element.style());
+            let (style, inherited_actions) = match self.parent(id) {
+                Some(parent) => {
+                    let parent = &self.node(parent).element;
+                    (
+                        cascade::cascade(declarations, Some(parent.style()), root),
+                        parent.actions().clone(),
+                    )
                 }
-                actions
+                None => (cascade::cascade(declarations, None, root), vec![]),
+            };
+            let node = &mut self.nodes[id.0];
+            node.element.resolve(style, &inherited_actions);
+            node.dirty.style = false;
+        }
+    }
+
+    /// The declarations that apply to a node in source order: the
+    /// user-agent, user and author style sheets, then its `style` attribute.
+    /// Text nodes only inherit.
+    fn cascaded_declarations(&self, id: NodeId) -> Vec<CascadedDeclaration> {
+        let DOMElement::View { .. } = &self.node(id).element else {
+            return vec![];
+        };
+        let mut cascaded = vec![];
+        for (stylesheet, origin) in self.stylesheets() {
+            let mut matching = vec![];
+            stylesheet.matching_declarations(self, id, self.viewport_width, &mut matching);
+            cascaded.extend(matching.into_iter().map(|(declaration, specificity)| {
+                CascadedDeclaration {
+                    declaration: declaration.clone(),
+                    origin,
+                    inline: false,
+                    specificity,
+                }
+            }));
+        }
+        let inline = self
+            .node(id)
+            .element
+            .get_attribute("style")
+            .map(css::parse_style_attribute)
+            .unwrap_or_default();
+        cascaded.extend(inline.into_iter().map(|declaration| CascadedDeclaration {
+            declaration,
+            origin: Origin::Author,
+            inline: true,
+            specificity: Specificity::default(),
+        }));
+        cascaded
+    }
+
+    /// The style sheets that apply to the document, in cascade order.
+    fn stylesheets(&self) -> impl Iterator<Item = (&Stylesheet, Origin)> {
+        std::iter::once((user_agent_stylesheet(), Origin::UserAgent))
+            .chain(
+                self.user_stylesheet
+                    .iter()
+                    .map(|sheet| (sheet, Origin::User)),
+            )
+            .chain(
+                self.author_stylesheets
+                    .iter()
+                    .map(|sheet| (sheet, Origin::Author)),
+            )
+    }
+
+    /// Replaces the reader's style sheet, which applies to every page.
+    pub(crate) fn set_user_stylesheet(&mut self, stylesheet: Option<Stylesheet>) {
+        self.user_stylesheet = stylesheet;
+        for root in self.roots.clone() {
+            self.mark_dirty(root);
+        }
+    }
+
+    /// Sets the width `@media` queries are evaluated for, restyling the
+    /// document if it changed.
+    pub(crate) fn set_viewport_width(&mut self, width: f32) {
+        if width == self.viewport_width {
+            return;
+        }
+        self.viewport_width = width;
+        // The user-agent style sheet has no `@media` rules
+        if !self.author_stylesheets.is_empty() || self.user_stylesheet.is_some() {
+            for root in self.roots.clone() {
+                self.mark_dirty(root);
             }
-            _ => vec![],
         }
     }
-}
 
-struct MaybeStyle {
-    pub display: Option<Display>,
-    pub margin: Option<Margin>,
-    pub font: Option<Font>,
-    pub color: Option<Color>,
-    pub text_decoration: Option<TextDecoration>,
-}
+    /// Whether anything has to be laid out again since the last call.
+    pub(crate) fn take_layout_dirty(&mut self) -> bool {
+        let mut dirty = false;
+        for node in &mut self.nodes {
+            dirty |= std::mem::take(&mut node.dirty.layout);
+        }
+        dirty
+    }
 
-pub(crate) struct InheritableStyle {
-    pub font: Font,
-    pub color: Color,
-    pub text_decoration: TextDecoration,
-}
+    /// Marks a subtree for restyle and relayout, and its ancestors for relayout
+    /// since their boxes depend on it.
+    fn mark_dirty(&mut self, id: NodeId) {
+        self.mark_subtree_dirty(id);
+        self.mark_ancestors_layout_dirty(id);
+    }
 
-impl Default for InheritableStyle {
-    fn default() -> Self {
-        Self {
-            font: Font::default(),
-            color: Color::default(),
-            text_decoration: TextDecoration::default(),
+    fn mark_subtree_dirty(&mut self, id: NodeId) {
+        let subtree: Vec<_> = std::iter::once(id).chain(self.descendants(id)).collect();
+        for id in subtree {
+            self.nodes[id.0].dirty = Dirty {
+                style: true,
+                layout: true,
+            };
         }
     }
-}
 
-impl MaybeStyle {
-    pub(crate) fn from_tag(tag: &str) -> Self {
-        match tag {
-            "p" | "P" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                )),
-                font: None,
-                color: None,
-                text_decoration: None,
-            },
-            "h1" | "H1" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(0.67),
-                    Unit::Em(0.0),
-                    Unit::Em(0.67),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(2.0),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "h2" | "H2" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(0.83),
-                    Unit::Em(0.0),
-                    Unit::Em(0.83),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(1.5),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "h3" | "H3" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(1.17),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "h4" | "H4" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(1.33),
-                    Unit::Em(0.0),
-                    Unit::Em(1.33),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(1.0),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "h5" | "H5" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(1.67),
-                    Unit::Em(0.0),
-                    Unit::Em(1.67),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(0.83),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "h6" | "H6" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(2.33),
-                    Unit::Em(0.0),
-                    Unit::Em(2.33),
-                    Unit::Em(0.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(0.67),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Bold,
-                    FontStyle::Normal,
-                )),
-                color: None,
-                text_decoration: None,
-            },
-            "a" | "A" => Self {
-                display: None,
-                margin: None,
-                font: None,
-                color: Some(Color::new(0, 0, 238)),
-                text_decoration: Some(TextDecoration {
-                    color: Color::new(0, 0, 238),
-                    line: TextDecorationLine::Underline,
-                    style: TextDecorationStyle::Solid,
-                }),
-            },
-            "dl" | "DL" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                    Unit::Em(1.0),
-                    Unit::Em(0.0),
-                )),
-                font: None,
-                color: None,
-                text_decoration: None,
-            },
-            "dt" | "DT" => Self {
-                display: Some(Display::Block),
-                margin: None,
-                font: None,
-                color: None,
-                text_decoration: None,
-            },
-            "dd" | "DD" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Em(0.0),
-                    Unit::Em(0.0),
-                    Unit::Em(0.0),
-                    Unit::Px(40.0),
-                )),
-                font: None,
-                color: None,
-                text_decoration: None,
-            },
-            "body" | "BODY" => Self {
-                display: Some(Display::Block),
-                margin: Some(Margin::new(
-                    Unit::Px(8.0),
-                    Unit::Px(8.0),
-                    Unit::Px(8.0),
-                    Unit::Px(8.0),
-                )),
-                font: Some(Font::new(
-                    Unit::Em(1.0),
-                    FontFamily::TimesNewRoman,
-                    FontWeight::Normal,
-                    FontStyle::Normal,
-                )),
-                color: Some(Color::new(0, 0, 0)),
-                text_decoration: None,
-            },
-            _ => Self {
-                display: None,
-                margin: None,
-                font: None,
-                color: None,
-                text_decoration: None,
-            },
+    fn mark_layout_dirty(&mut self, id: NodeId) {
+        self.nodes[id.0].dirty.layout = true;
+        self.mark_ancestors_layout_dirty(id);
+    }
+
+    fn mark_ancestors_layout_dirty(&mut self, id: NodeId) {
+        let ancestors: Vec<_> = self.ancestors(id).collect();
+        for id in ancestors {
+            self.nodes[id.0].dirty.layout = true;
         }
     }
 }
 
-impl HTMLElement {
-    pub(crate) fn into_dom_element(
-        self,
-        inherited_style: &InheritableStyle,
-        mut inherited_actions: Vec<DOMAction>,
-    ) -> DOMElement {
-        match self {
-            HTMLElement::Element {
-                tag,
-                attributes,
-                children,
-            } => {
-                // Get style
-                let new_style = MaybeStyle::from_tag(&tag);
-                // Inherit if not present
-                let style = Style {
-                    display: new_style.display.unwrap_or(Display::default()),
-                    margin: new_style.margin.unwrap_or(Margin::default()),
-                    font: new_style.font.unwrap_or(inherited_style.font.clone()),
-                    color: new_style.color.unwrap_or(inherited_style.color),
-                    text_decoration: new_style
-                        .text_decoration
-                        .unwrap_or(inherited_style.text_decoration.clone()),
-                };
-                // Create new inherited style
-                let inherited_style = InheritableStyle {
-                    font: style.font.clone(),
-                    color: style.color.clone(),
-                    text_decoration: style.text_decoration.clone(),
-                };
-                // Get actions
-                let actions = DOMAction::from_html_element(&tag, &attributes);
-                inherited_actions.extend(actions);
-                // Recurse on children
-                let children = children
-                    .into_iter()
-                    .filter(|child| !child.is_header())
-                    .map(|child| {
-                        child.into_dom_element(&inherited_style, inherited_actions.clone())
-                    })
-                    .collect();
-                // Return DOMElement
+impl DOM {
+    pub(crate) fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub(crate) fn node(&self, id: NodeId) -> &DOMNode {
+        &self.nodes[id.0]
+    }
+
+    pub(crate) fn get(&self, id: NodeId) -> Option<&DOMElement> {
+        self.nodes.get(id.0).map(|node| &node.element)
+    }
+
+    pub(crate) fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent
+    }
+
+    pub(crate) fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.node(id).first_child, |&child| {
+            self.node(child).next_sibling
+        })
+    }
+
+    /// The first element in document order matching a selector list.
+    #[allow(dead_code, reason = "for embedders, the cascade matches itself")]
+    pub(crate) fn query_selector(&self, selectors: &str) -> Result<Option<NodeId>, SelectorError> {
+        Ok(self.query_selector_all(selectors)?.into_iter().next())
+    }
+
+    /// All elements matching a selector list, in document order.
+    pub(crate) fn query_selector_all(&self, selectors: &str) -> Result<Vec<NodeId>, SelectorError> {
+        let selectors = SelectorList::parse(selectors)?;
+        Ok(self
+            .pre_order()
+            .filter(|&id| selectors.matches(self, id))
+            .collect())
+    }
+
+    /// The child indices leading from the roots to a node, like `0/2/1`.
+    /// Unlike `NodeId`s, paths don't shift when a streamed page grows, since
+    /// later content only ever adds nodes after existing siblings.
+    pub(crate) fn path(&self, id: NodeId) -> String {
+        let mut indices = vec![];
+        let mut current = Some(id);
+        while let Some(id) = current {
+            let node = self.node(id);
+            let index = std::iter::successors(node.previous_sibling, |&sibling| {
+                self.node(sibling).previous_sibling
+            })
+            .count();
+            indices.push(index.to_string());
+            current = node.parent;
+        }
+        indices.reverse();
+        indices.join("/")
+    }
+
+    /// The element a `#fragment` points at: the first with that `id`, or else
+    /// the first `<a name>`.
+    pub(crate) fn fragment_target(&self, fragment: &str) -> Option<NodeId> {
+        // Walking the document leaves out detached nodes, which can't be
+        // scrolled to
+        let with_attribute = |tags: &[&str], name: &str| {
+            self.pre_order().find(|&id| {
+                let element = &self.node(id).element;
+                (tags.is_empty() || tags.contains(&element.tag()))
+                    && element.get_attribute(name) == Some(fragment)
+            })
+        };
+        with_attribute(&[], "id").or_else(|| with_attribute(&["a"], "name"))
+    }
+
+    pub(crate) fn hovered(&self) -> Option<NodeId> {
+        self.hovered
+    }
+
+    /// Moves the hover state to `target` and its ancestors. Only elements
+    /// a `:hover` selector matches before or after the move are restyled.
+    pub(crate) fn set_hovered(&mut self, target: Option<NodeId>) {
+        if target == self.hovered {
+            return;
+        }
+        let with_ancestors = |id: Option<NodeId>| -> Vec<_> {
+            id.into_iter()
+                .chain(id.into_iter().flat_map(|id| self.ancestors(id)))
+                .collect()
+        };
+        let old = with_ancestors(self.hovered);
+        let new = with_ancestors(target);
+        let changed: Vec<_> = old
+            .iter()
+            .filter(|id| !new.contains(id))
+            .chain(new.iter().filter(|id| !old.contains(id)))
+            .copied()
+            .collect();
+        let mut restyle = self.hover_styled(&changed);
+        for &id in &changed {
+            self.nodes[id.0].state.hovered = new.contains(&id);
+        }
+        restyle.extend(self.hover_styled(&changed));
+        restyle.sort();
+        restyle.dedup();
+        for id in restyle {
+            self.mark_dirty(id);
+        }
+        self.hovered = target;
+    }
+
+    /// The elements a selector with `:hover` in it matches, of those whose
+    /// style can depend on the hover state of the `changed` nodes.
+    fn hover_styled(&self, changed: &[NodeId]) -> Vec<NodeId> {
+        let selectors: Vec<_> = self
+            .stylesheets()
+            .flat_map(|(stylesheet, _)| stylesheet.selectors())
+            .filter(|selector| selector.hover_dependence() != HoverDependence::None)
+            .collect();
+        let candidates: Vec<_> = if selectors
+            .iter()
+            .all(|selector| selector.hover_dependence() == HoverDependence::Subject)
+        {
+            changed.to_vec()
+        } else {
+            self.pre_order().collect()
+        };
+        candidates
+            .into_iter()
+            .filter(|&id| selectors.iter().any(|selector| selector.matches(self, id)))
+            .collect()
+    }
+
+    /// Marks the links whose `href` `is_visited` says was visited, for
+    /// `:visited` styling.
+    pub(crate) fn update_visited(&mut self, is_visited: impl Fn(&str) -> bool) {
+        for index in 0..self.nodes.len() {
+            let visited = match &self.nodes[index].element {
                 DOMElement::View {
-                    id: uuid::Uuid::new_v4().to_string(),
-                    tag,
-                    style,
-                    children,
-                    actions: inherited_actions,
-                }
-            }
-            HTMLElement::Text(text) => {
-                let style = Style {
-                    display: Display::Inline,
-                    margin: Margin::default(),
-                    font: inherited_style.font.clone(),
-                    color: inherited_style.color,
-                    text_decoration: inherited_style.text_decoration.clone(),
-                };
-                DOMElement::Text {
-                    id: uuid::Uuid::new_v4().to_string(),
-                    style,
-                    text,
-                    actions: inherited_actions,
-                }
+                    tag, attributes, ..
+                } if is_link(tag, attributes) => is_visited(&attributes["href"]),
+                _ => false,
+            };
+            if visited != self.nodes[index].state.visited {
+                self.nodes[index].state.visited = visited;
+                self.mark_dirty(NodeId(index));
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct DOM {
-    pub elements: Vec<DOMElement>,
+/// Writes the tags of a DOM with their node ids, for debugging.
+struct TagWriter<'a, 'b> {
+    f: &'a mut std::fmt::Formatter<'b>,
+    result: std::fmt::Result,
 }
 
-impl DOM {
-    pub(crate) fn construct_dom(html_elements: Vec<HTMLElement>) -> Self {
-        let elements = html_elements
-            .into_iter()
-            .filter(|element| !element.is_header())
-            .map(|element| element.into_dom_element(&InheritableStyle::default(), vec![]))
-            .collect();
-        Self { elements }
+impl Visitor for TagWriter<'_, '_> {
+    fn enter(&mut self, dom: &DOM, id: NodeId) -> bool {
+        self.result = self.result.and_then(|()| match &dom.node(id).element {
+            DOMElement::View { tag, .. } => write!(self.f, "<{} id=\"{}\">", tag, id),
+            DOMElement::Text { text, .. } => write!(self.f, "{}", text),
+        });
+        self.result.is_ok()
+    }
+
+    fn leave(&mut self, dom: &DOM, id: NodeId) {
+        if let DOMElement::View { tag, .. } = &dom.node(id).element {
+            self.result = self.result.and_then(|()| write!(self.f, "</{}>", tag));
+        }
     }
 }
 
-impl DOM {
-    pub(crate) fn get(&self, id: &str) -> Option<&DOMElement> {
-        for element in &self.elements {
-            if let Some(e) = element.get(id) {
-                return Some(e);
-            }
+impl std::fmt::Display for DOM {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut writer = TagWriter { f, result: Ok(()) };
+        self.walk(&mut writer);
+        writer.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dump, encoding, parser,
+        styling::{Display, FontWeight},
+    };
+
+    const PAGE: &str = "<html><body><h1>Title</h1><p>Some <a href=\"/x\">link</a> \
+        text</p><dl><dt>Term<dd>Definition</dl></body></html>";
+
+    fn pre_order(dom: &DOM, id: NodeId, ids: &mut Vec<NodeId>) {
+        ids.push(id);
+        for child in dom.children(id) {
+            pre_order(dom, child, ids);
         }
-        None
     }
 
-    pub(crate) fn set_clicked(&mut self, id: &str) {
-        for element in &mut self.elements {
-            element.set_clicked(id);
+    #[test]
+    fn ids_follow_document_order() {
+        let dom = DOM::construct_dom(parser::parse(PAGE));
+        let mut ids = vec![];
+        for &root in dom.roots() {
+            pre_order(&dom, root, &mut ids);
         }
+        let expected: Vec<_> = (0..ids.len()).map(NodeId).collect();
+        assert_eq!(ids, expected);
     }
 
-    pub(crate) fn set_hovered(&mut self, id: &str) {
-        for element in &mut self.elements {
-            element.set_hovered(id);
+    #[test]
+    fn updating_from_streamed_snapshots_matches_a_full_parse() {
+        // The comment gets the encoding sniffed before the content arrives
+        let page = format!(
+            "<!--{}--><html><head><style>li:last-child {{ color: red }}</style></head>\
+            <body><p title=\"a>b\" class='c'>Some <b>bold <i>and italic</b> text</p><ul><li>one<li>two\
+            <li>three</ul><pre>a\n\n b</pre></body></html>",
+            " ".repeat(encoding::PRESCAN_LENGTH)
+        );
+        let expected = DOM::construct_dom(parser::parse(&page));
+        // Every size splits some tag somewhere else, like inside the title
+        for size in 1..=8 {
+            let mut parser = parser::StreamingParser::new(None);
+            let mut dom = DOM::construct_dom(vec![]);
+            for chunk in page.as_bytes().chunks(size) {
+                if parser.feed(chunk) {
+                    dom.update(parser.snapshot());
+                }
+            }
+            dom.update(parser.finish().0);
+            assert_eq!(
+                dump::to_json(&dom),
+                dump::to_json(&expected),
+                "chunks of {size}"
+            );
         }
     }
-}
 
-impl ToString for DOM {
-    fn to_string(&self) -> String {
-        let mut result = String::new();
-        for element in &self.elements {
-            result.push_str(&element.to_string());
+    /// Checks that every attached node's links agree with its relatives'.
+    fn assert_links_consistent(dom: &DOM) {
+        let mut siblings = vec![dom.roots().to_vec()];
+        for id in dom.pre_order() {
+            siblings.push(dom.children(id).collect());
+            let node = dom.node(id);
+            assert_eq!(node.first_child.is_some(), node.last_child.is_some());
+        }
+        for (index, list) in siblings.iter().enumerate() {
+            for (position, &id) in list.iter().enumerate() {
+                let node = dom.node(id);
+                let previous = position.checked_sub(1).map(|position| list[position]);
+                assert_eq!(node.previous_sibling, previous, "previous of {}", id);
+                assert_eq!(node.next_sibling, list.get(position + 1).copied());
+                if index == 0 {
+                    assert_eq!(node.parent, None);
+                } else {
+                    let parent = node.parent.unwrap();
+                    assert_eq!(dom.node(parent).first_child, list.first().copied());
+                    assert_eq!(dom.node(parent).last_child, list.last().copied());
+                }
+            }
         }
-        result
+    }
+
+    /// A styled DOM with nothing dirty, and its elements by `id`.
+    fn clean_dom(html: &str) -> (DOM, impl Fn(&DOM, &str) -> NodeId) {
+        let mut dom = DOM::construct_dom(parser::parse(html));
+        dom.take_layout_dirty();
+        let by_id = |dom: &DOM, id: &str| dom.query_selector(&format!("#{}", id)).unwrap().unwrap();
+        (dom, by_id)
+    }
+
+    fn dirty(dom: &DOM, id: NodeId) -> (bool, bool) {
+        let dirty = dom.node(id).dirty;
+        (dirty.style, dirty.layout)
+    }
+
+    #[test]
+    fn mutations_keep_links_consistent() {
+        let (mut dom, by_id) =
+            clean_dom("<div id=a><p id=b>b</p><p id=c>c</p></div><div id=d></div>");
+        let (a, b, c, d) = (
+            by_id(&dom, "a"),
+            by_id(&dom, "b"),
+            by_id(&dom, "c"),
+            by_id(&dom, "d"),
+        );
+
+        let e = dom.create_element("SPAN");
+        assert_eq!(dom.node(e).element.tag(), "span");
+        dom.insert_before(a, e, Some(c)).unwrap();
+        assert_eq!(dom.children(a).collect::<Vec<_>>(), [b, e, c]);
+        assert_links_consistent(&dom);
+
+        // Moving a node takes it out of its old parent
+        dom.append_child(d, b).unwrap();
+        assert_eq!(dom.children(a).collect::<Vec<_>>(), [e, c]);
+        assert_eq!(dom.children(d).collect::<Vec<_>>(), [b]);
+        assert_links_consistent(&dom);
+
+        // Inserting a node before itself leaves it in place
+        dom.insert_before(a, c, Some(c)).unwrap();
+        assert_eq!(dom.children(a).collect::<Vec<_>>(), [e, c]);
+        dom.insert_before(a, c, Some(e)).unwrap();
+        assert_eq!(dom.children(a).collect::<Vec<_>>(), [c, e]);
+        assert_links_consistent(&dom);
+
+        dom.remove_child(a, c).unwrap();
+        dom.remove_child(a, e).unwrap();
+        assert_eq!(dom.children(a).count(), 0);
+        assert_eq!(dom.parent(c), None);
+        assert_links_consistent(&dom);
+
+        dom.set_text(d, "replaced");
+        assert_eq!(dom.text_content(d), "replaced");
+        assert_eq!(dom.parent(b), None);
+        assert_links_consistent(&dom);
+    }
+
+    #[test]
+    fn mutations_refuse_cycles_and_strangers() {
+        let (mut dom, by_id) = clean_dom("<div id=a><p id=b>text</p></div><div id=c></div>");
+        let (a, b, c) = (by_id(&dom, "a"), by_id(&dom, "b"), by_id(&dom, "c"));
+        let text = dom.children(b).next().unwrap();
+        let detached = dom.create_element("span");
+
+        assert_eq!(dom.append_child(a, a), Err(MutationError::HierarchyRequest));
+        assert_eq!(dom.append_child(b, a), Err(MutationError::HierarchyRequest));
+        assert_eq!(
+            dom.append_child(text, c),
+            Err(MutationError::HierarchyRequest)
+        );
+        assert_eq!(
+            dom.insert_before(a, c, Some(c)),
+            Err(MutationError::NotFound)
+        );
+        assert_eq!(
+            dom.insert_before(a, c, Some(detached)),
+            Err(MutationError::NotFound)
+        );
+        assert_eq!(dom.remove_child(c, b), Err(MutationError::NotFound));
+        assert_eq!(dom.remove_child(a, detached), Err(MutationError::NotFound));
+        // Refused mutations leave the tree alone
+        assert_eq!(dom.children(a).collect::<Vec<_>>(), [b]);
+        assert_links_consistent(&dom);
+
+        // Detached nodes can be inserted, and moved around while detached
+        dom.append_child(detached, c).unwrap();
+        dom.append_child(b, detached).unwrap();
+        assert_eq!(dom.ancestors(c).collect::<Vec<_>>(), [detached, b, a]);
+        assert_links_consistent(&dom);
+    }
+
+    #[test]
+    fn mutations_mark_what_they_affect() {
+        let page = "<div id=a><p id=b><i id=c>c</i></p><p id=d>d</p></div><div id=e></div>";
+
+        let (mut dom, by_id) = clean_dom(page);
+        let (a, b, c, d, e) = (
+            by_id(&dom, "a"),
+            by_id(&dom, "b"),
+            by_id(&dom, "c"),
+            by_id(&dom, "d"),
+            by_id(&dom, "e"),
+        );
+        let text = dom.children(c).next().unwrap();
+        // New text only has to be laid out again, along with what contains it
+        dom.set_text(text, "changed");
+        assert_eq!(dirty(&dom, text), (false, true));
+        assert_eq!(dirty(&dom, c), (false, true));
+        assert_eq!(dirty(&dom, a), (false, true));
+        assert_eq!(dirty(&dom, d), (false, false));
+        assert_eq!(dirty(&dom, e), (false, false));
+        assert!(dom.take_layout_dirty());
+        assert!(!dom.take_layout_dirty());
+
+        // Attributes can change what matches the element and its descendants
+        dom.set_attribute(b, "class", "x");
+        assert_eq!(dirty(&dom, b), (true, true));
+        assert_eq!(dirty(&dom, text), (true, true));
+        assert_eq!(dirty(&dom, a), (false, true));
+        assert_eq!(dirty(&dom, d), (false, false));
+        dom.restyle();
+        assert_eq!(dirty(&dom, b), (false, true));
+
+        // A fresh load of the same page has the same ids
+        let (mut dom, _) = clean_dom(page);
+        // Siblings can match different selectors after an insertion
+        dom.append_child(a, c).unwrap();
+        assert_eq!(dirty(&dom, d), (true, true));
+        assert_eq!(dirty(&dom, c), (true, true));
+        // and so can the node's old siblings
+        assert_eq!(dirty(&dom, b), (true, true));
+        assert_eq!(dirty(&dom, e), (false, false));
+
+        let (mut dom, _) = clean_dom(page);
+        dom.remove_child(a, d).unwrap();
+        assert_eq!(dirty(&dom, b), (true, true));
+        assert_eq!(dirty(&dom, e), (false, false));
+    }
+
+    #[test]
+    fn hovering_restyles_what_hover_selectors_match() {
+        let color =
+            |dom: &DOM, id| macroquad::color::Color::from(dom.node(id).element.style().color);
+        let red = macroquad::color::Color::from_rgba(255, 0, 0, 255);
+        let page = "<div id=a><p id=b>Some <a id=c href=/x>link</a></p><p id=d>text</p></div>";
+        let (mut dom, by_id) = clean_dom(page);
+        let (a, b, c, d) = (
+            by_id(&dom, "a"),
+            by_id(&dom, "b"),
+            by_id(&dom, "c"),
+            by_id(&dom, "d"),
+        );
+        let link_text = dom.children(c).next().unwrap();
+
+        // Only `:link:hover` from the user-agent style sheet depends on hover
+        dom.set_hovered(Some(d));
+        assert!(dom.node(d).state.hovered && dom.node(a).state.hovered);
+        assert!(dom.pre_order().all(|id| dirty(&dom, id) == (false, false)));
+
+        dom.set_hovered(Some(c));
+        assert!(!dom.node(d).state.hovered && dom.node(a).state.hovered);
+        assert_eq!(dirty(&dom, c), (true, true));
+        assert_eq!(dirty(&dom, link_text), (true, true));
+        assert_eq!(dirty(&dom, b), (false, true));
+        assert_eq!(dirty(&dom, d), (false, false));
+        dom.restyle();
+        assert_eq!(color(&dom, link_text), red);
+
+        dom.take_layout_dirty();
+        dom.set_hovered(None);
+        assert_eq!(dirty(&dom, c), (true, true));
+        assert_eq!(dirty(&dom, a), (false, true));
+        dom.restyle();
+        assert_ne!(color(&dom, link_text), red);
+
+        // Selectors depending on other elements' state restyle what they match
+        let page = format!("<style>div:hover > p {{ color: red }}</style>{}", page);
+        let (mut dom, by_id) = clean_dom(&page);
+        let (b, d) = (by_id(&dom, "b"), by_id(&dom, "d"));
+        dom.set_hovered(Some(d));
+        assert_eq!(dirty(&dom, b), (true, true));
+        assert_eq!(dirty(&dom, d), (true, true));
+        assert_eq!(dirty(&dom, by_id(&dom, "c")), (true, true));
+        dom.restyle();
+        assert_eq!(color(&dom, b), red);
+    }
+
+    #[test]
+    fn user_agent_stylesheet_is_parsed_once_and_whole() {
+        assert!(std::ptr::eq(
+            user_agent_stylesheet(),
+            user_agent_stylesheet()
+        ));
+        // Every rule in it is understood, none were dropped
+        let rules = &user_agent_stylesheet().rules;
+        assert_eq!(rules.len(), USER_AGENT_CSS.matches('{').count());
+        for rule in rules {
+            let css::CSSRule::Style(rule) = rule else {
+                panic!("unexpected {:?}", rule);
+            };
+            assert!(!rule.declarations.is_empty(), "{:?}", rule.selectors);
+        }
+    }
+
+    #[test]
+    fn user_agent_stylesheet_defaults() {
+        let (mut dom, by_id) = clean_dom(
+            "<body id=body><h1 id=h1>Title</h1><p id=p>Some <a id=a href=/x>link</a></p>\
+             <ul id=outer><li><ul id=inner><li>nested</ul></ul></body>",
+        );
+        let style = |dom: &DOM, id: &str| dom.node(by_id(dom, id)).element.style().clone();
+
+        assert_eq!(style(&dom, "body").margin.left.to_string(), "8px");
+        let h1 = style(&dom, "h1");
+        assert_eq!(h1.font.size.to_string(), "32px");
+        assert_eq!(h1.margin.top.to_string(), "21.44px");
+        assert!(matches!(h1.font.weight, FontWeight::Bold));
+        assert!(matches!(h1.display, Display::Block));
+        assert_eq!(style(&dom, "p").margin.bottom.to_string(), "16px");
+        assert_eq!(style(&dom, "outer").margin.top.to_string(), "16px");
+        assert_eq!(style(&dom, "outer").margin.left.to_string(), "40px");
+        assert_eq!(style(&dom, "inner").margin.top.to_string(), "0px");
+
+        let a = by_id(&dom, "a");
+        assert_eq!(style(&dom, "a").color.to_string(), "#0000ee");
+        dom.update_visited(|href| href == "/x");
+        dom.restyle();
+        assert_eq!(style(&dom, "a").color.to_string(), "#551a8b");
+        dom.set_hovered(Some(a));
+        dom.restyle();
+        assert_eq!(style(&dom, "a").color.to_string(), "#ff0000");
+    }
+
+    #[test]
+    fn ids_are_the_same_across_loads() {
+        let first = DOM::construct_dom(parser::parse(PAGE));
+        let second = DOM::construct_dom(parser::parse(PAGE));
+        assert_eq!(first.to_string(), second.to_string());
     }
 }
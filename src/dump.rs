@@ -0,0 +1,60 @@
+use serde_json::{json, Value};
+
+use crate::{
+    dom::{DOMElement, NodeId, DOM},
+    traversal::Visitor,
+};
+
+/// The DOM as JSON with the computed style and actions of every node, to
+/// compare style resolution between versions.
+pub(crate) fn to_json(dom: &DOM) -> Value {
+    let mut builder = JsonBuilder { open: vec![vec![]] };
+    dom.walk(&mut builder);
+    Value::Array(builder.open.pop().unwrap_or_default())
+}
+
+/// Builds nested JSON nodes while walking the DOM.
+struct JsonBuilder {
+    /// The children collected so far for each node being visited, under the
+    /// roots collected so far
+    open: Vec<Vec<Value>>,
+}
+
+impl Visitor for JsonBuilder {
+    fn enter(&mut self, _dom: &DOM, _id: NodeId) -> bool {
+        self.open.push(vec![]);
+        true
+    }
+
+    fn leave(&mut self, dom: &DOM, id: NodeId) {
+        let children = self.open.pop().unwrap_or_default();
+        let node = match &dom.node(id).element {
+            DOMElement::View {
+                tag,
+                attributes,
+                style,
+                actions,
+            } => json!({
+                "type": "element",
+                "tag": tag,
+                "attributes": attributes,
+                "style": style,
+                "actions": actions,
+                "children": children,
+            }),
+            DOMElement::Text {
+                style,
+                text,
+                actions,
+            } => json!({
+                "type": "text",
+                "text": text,
+                "style": style,
+                "actions": actions,
+            }),
+        };
+        if let Some(parent) = self.open.last_mut() {
+            parent.push(node);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8, WINDOWS_1252};
+
+/// How far into the document to look for a `<meta>` charset declaration.
+pub(crate) const PRESCAN_LENGTH: usize = 1024;
+
+/// Decodes a fetched document to UTF-8, sniffing its encoding from, in order,
+/// a byte order mark, the HTTP `Content-Type` charset, a `<meta>` declaration
+/// near the start of the document and finally the windows-1252 default that
+/// early web pages (labelled ISO-8859-1 or not at all) were written in. An
+/// undeclared document that happens to be valid UTF-8 is read as UTF-8.
+pub(crate) fn decode(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = sniff(bytes, content_type, true);
+    // `decode` strips a BOM and replaces malformed sequences
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// The encoding `decode` would pick. `complete` is false while more of the
+/// document is still to come, so a character cut off at the end of `bytes`
+/// doesn't count against UTF-8.
+pub(crate) fn sniff(bytes: &[u8], content_type: Option<&str>, complete: bool) -> &'static Encoding {
+    declared(bytes, content_type).unwrap_or_else(|| match std::str::from_utf8(bytes) {
+        Ok(_) => UTF_8,
+        Err(error) if error.error_len().is_none() && !complete => UTF_8,
+        Err(_) => WINDOWS_1252,
+    })
+}
+
+/// The encoding a byte order mark, the `Content-Type` charset or a `<meta>`
+/// near the start of the document declares, in that order.
+pub(crate) fn declared(bytes: &[u8], content_type: Option<&str>) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Some(encoding);
+    }
+
+    content_type
+        .and_then(charset_from_content_type)
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+        .or_else(|| prescan(&bytes[..bytes.len().min(PRESCAN_LENGTH)]))
+}
+
+/// Decodes the next chunk of a document into `output`. `last` flushes any
+/// partial character left at the end. Returns whether any malformed sequences
+/// were replaced.
+pub(crate) fn decode_chunk(
+    decoder: &mut Decoder,
+    bytes: &[u8],
+    output: &mut String,
+    last: bool,
+) -> bool {
+    let mut bytes = bytes;
+    let mut malformed = false;
+    loop {
+        if let Some(length) = decoder.max_utf8_buffer_length(bytes.len()) {
+            output.reserve(length);
+        }
+        let (result, read, replaced) = decoder.decode_to_string(bytes, output, last);
+        bytes = &bytes[read..];
+        malformed |= replaced;
+        match result {
+            CoderResult::InputEmpty => return malformed,
+            CoderResult::OutputFull => continue,
+        }
+    }
+}
+
+/// Extracts the charset parameter of a `text/html; charset=...` value.
+pub(crate) fn charset_from_content_type(content_type: &str) -> Option<String> {
+    let lower = content_type.to_ascii_lowercase();
+    let index = lower.find("charset")?;
+    let rest = lower[index + "charset".len()..].trim_start();
+    let rest = rest.strip_prefix('=')?.trim_start();
+
+    let charset = match rest.strip_prefix(['"', '\'']) {
+        Some(quoted) => quoted.split(['"', '\'']).next()?,
+        None => rest.split([';', ' ', '\t']).next()?,
+    };
+    (!charset.is_empty()).then(|| charset.to_string())
+}
+
+/// A simplified version of the HTML spec's prescan: finds the first `<meta>`
+/// with a `charset` attribute or an `http-equiv="content-type"` whose
+/// `content` names a charset.
+fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    // Everything we look for is ASCII, so a lossy view is good enough
+    let head = String::from_utf8_lossy(bytes).to_ascii_lowercase();
+
+    let mut rest = head.as_str();
+    while let Some(index) = rest.find("<meta") {
+        rest = &rest[index + "<meta".len()..];
+        let end = rest.find('>').unwrap_or(rest.len());
+        let attributes = meta_attributes(&rest[..end]);
+        let get = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let charset = match (get("charset"), get("http-equiv"), get("content")) {
+            (Some(charset), _, _) => Some(charset.to_string()),
+            (None, Some("content-type"), Some(content)) => charset_from_content_type(content),
+            _ => None,
+        };
+
+        if let Some(encoding) = charset.and_then(|charset| Encoding::for_label(charset.as_bytes()))
+        {
+            // A document that says it's UTF-16 can't be, since we read ASCII out of it
+            return Some(match encoding.name() {
+                "UTF-16LE" | "UTF-16BE" => UTF_8,
+                "x-user-defined" => WINDOWS_1252,
+                _ => encoding,
+            });
+        }
+    }
+    None
+}
+
+/// Splits the inside of a `<meta ...>` tag into name/value pairs.
+fn meta_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    let mut rest = tag.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let mut value = "";
+        if let Some(after_equals) = rest.strip_prefix('=') {
+            let after_equals = after_equals.trim_start();
+            (value, rest) = match after_equals.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let inner = &after_equals[1..];
+                    let end = inner.find(quote).unwrap_or(inner.len());
+                    (&inner[..end], inner.get(end + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let end = after_equals
+                        .find(|c: char| c.is_ascii_whitespace())
+                        .unwrap_or(after_equals.len());
+                    after_equals.split_at(end)
+                }
+            };
+        }
+
+        if !name.is_empty() {
+            attributes.push((name.to_string(), value.to_string()));
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+    }
+
+    attributes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{KOI8_R, UTF_16LE};
+
+    #[test]
+    fn a_byte_order_mark_beats_every_declaration() {
+        let mut bytes = b"\xef\xbb\xbf<meta charset=iso-8859-1>".to_vec();
+        bytes.extend_from_slice("café".as_bytes());
+        assert_eq!(
+            sniff(&bytes, Some("text/html; charset=windows-1252"), true),
+            UTF_8
+        );
+        assert_eq!(decode(&bytes, None), "<meta charset=iso-8859-1>café");
+        assert_eq!(sniff(b"\xff\xfeh\0i\0", None, true), UTF_16LE);
+        assert_eq!(decode(b"\xff\xfeh\0i\0", None), "hi");
+    }
+
+    #[test]
+    fn the_content_type_charset_beats_a_meta_declaration() {
+        let bytes = b"<meta charset=\"utf-8\">caf\xe9";
+        assert_eq!(
+            sniff(bytes, Some("text/html; charset=ISO-8859-1"), true),
+            WINDOWS_1252
+        );
+        assert_eq!(
+            decode(bytes, Some("text/html; charset=ISO-8859-1")),
+            "<meta charset=\"utf-8\">café"
+        );
+        // An unknown label counts as no label
+        assert_eq!(sniff(bytes, Some("text/html; charset=bogus"), true), UTF_8);
+    }
+
+    #[test]
+    fn a_meta_declaration_is_found_near_the_start() {
+        let bytes =
+            b"<html><head><META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=koi8-r\">";
+        assert_eq!(sniff(bytes, Some("text/html"), true), KOI8_R);
+        assert_eq!(sniff(b"<meta charset='utf-16'>", None, true), UTF_8);
+
+        let mut late = vec![b' '; PRESCAN_LENGTH];
+        late.extend_from_slice(b"<meta charset=koi8-r>");
+        assert_ne!(sniff(&late, None, true), KOI8_R);
+    }
+
+    #[test]
+    fn undeclared_documents_are_utf_8_or_windows_1252() {
+        assert_eq!(sniff("café".as_bytes(), None, true), UTF_8);
+        assert_eq!(sniff(b"caf\xe9 au lait", None, true), WINDOWS_1252);
+        assert_eq!(
+            decode(b"caf\xe9 au lait \x93quoted\x94", None),
+            "café au lait \u{201c}quoted\u{201d}"
+        );
+    }
+
+    #[test]
+    fn charsets_are_read_out_of_content_types() {
+        assert_eq!(
+            charset_from_content_type("text/html;charset=\"Shift_JIS\""),
+            Some("shift_jis".to_string())
+        );
+        assert_eq!(
+            charset_from_content_type("text/html; Charset = latin1; q=1"),
+            Some("latin1".to_string())
+        );
+        assert_eq!(charset_from_content_type("text/html"), None);
+        assert_eq!(charset_from_content_type("text/html; charset="), None);
+    }
+
+    #[test]
+    fn a_character_cut_off_only_counts_against_utf_8_at_the_end() {
+        assert_eq!(sniff(b"caf\xe9", None, true), WINDOWS_1252);
+        assert_eq!(decode(b"caf\xe9", None), "café");
+        // The rest of the character may still arrive
+        assert_eq!(sniff(b"caf\xc3", None, false), UTF_8);
+        assert_eq!(sniff("café".as_bytes(), None, false), UTF_8);
+    }
+}
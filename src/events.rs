@@ -0,0 +1,324 @@
+use crate::dom::{DOMAction, NodeId, DOM};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum EventType {
+    Click,
+    MouseDown,
+    MouseUp,
+    MouseOver,
+    KeyDown,
+    Focus,
+}
+
+impl EventType {
+    /// Whether the event goes back up the tree after reaching its target.
+    fn bubbles(self) -> bool {
+        !matches!(self, EventType::Focus)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EventPhase {
+    /// Going down from the root to the target's parent
+    Capturing,
+    AtTarget,
+    /// Going back up from the target's parent to the root
+    Bubbling,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Event {
+    pub event_type: EventType,
+    /// The innermost node the event happened on
+    pub target: NodeId,
+    /// The node whose listeners are running
+    pub current_target: NodeId,
+    pub phase: EventPhase,
+    /// The key of a `KeyDown`, like `Enter`
+    pub key: Option<String>,
+    propagation_stopped: bool,
+    default_prevented: bool,
+}
+
+impl Event {
+    pub(crate) fn new(event_type: EventType, target: NodeId) -> Self {
+        Self {
+            event_type,
+            target,
+            current_target: target,
+            phase: EventPhase::AtTarget,
+            key: None,
+            propagation_stopped: false,
+            default_prevented: false,
+        }
+    }
+
+    pub(crate) fn key_down(target: NodeId, key: &str) -> Self {
+        Self {
+            key: Some(key.to_string()),
+            ..Self::new(EventType::KeyDown, target)
+        }
+    }
+
+    /// Keeps the event from reaching any further nodes. Listeners on the
+    /// current node still run.
+    #[allow(dead_code, reason = "for listeners, and Kale runs no scripts")]
+    pub(crate) fn stop_propagation(&mut self) {
+        self.propagation_stopped = true;
+    }
+
+    /// Cancels the default actions, like following a link on `Click`.
+    #[allow(dead_code, reason = "like `stop_propagation`, for listeners")]
+    pub(crate) fn prevent_default(&mut self) {
+        self.default_prevented = true;
+    }
+
+    pub(crate) fn default_prevented(&self) -> bool {
+        self.default_prevented
+    }
+}
+
+pub(crate) type Listener = Box<dyn FnMut(&mut Event, &mut DOM)>;
+
+/// A listener registered on a node with `DOM::add_event_listener`.
+pub(crate) struct EventListener {
+    event_type: EventType,
+    /// Runs while capturing instead of while bubbling
+    capture: bool,
+    callback: Listener,
+}
+
+impl std::fmt::Debug for EventListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventListener")
+            .field("event_type", &self.event_type)
+            .field("capture", &self.capture)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DOM {
+    #[allow(dead_code, reason = "Kale has no scripts to add listeners yet")]
+    pub(crate) fn add_event_listener(
+        &mut self,
+        id: NodeId,
+        event_type: EventType,
+        capture: bool,
+        callback: impl FnMut(&mut Event, &mut DOM) + 'static,
+    ) {
+        self.listeners.entry(id).or_default().push(EventListener {
+            event_type,
+            capture,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Runs the listeners for `event` down from the root to its target and,
+    /// if it bubbles, back up. Returns the default actions to run, which are
+    /// none if a listener prevented them.
+    pub(crate) fn dispatch_event(&mut self, mut event: Event) -> Vec<DOMAction> {
+        let ancestors: Vec<_> = self.ancestors(event.target).collect();
+
+        event.phase = EventPhase::Capturing;
+        for &id in ancestors.iter().rev() {
+            self.invoke_listeners(id, &mut event, true);
+            if event.propagation_stopped {
+                return self.default_actions(&event);
+            }
+        }
+
+        event.phase = EventPhase::AtTarget;
+        self.invoke_listeners(event.target, &mut event, true);
+        if !event.propagation_stopped {
+            self.invoke_listeners(event.target, &mut event, false);
+        }
+
+        if event.event_type.bubbles() {
+            event.phase = EventPhase::Bubbling;
+            for &id in &ancestors {
+                if event.propagation_stopped {
+                    break;
+                }
+                self.invoke_listeners(id, &mut event, false);
+            }
+        }
+        self.default_actions(&event)
+    }
+
+    /// Moves the focus to `target` and fires `Focus` at it.
+    pub(crate) fn focus(&mut self, target: NodeId) -> Vec<DOMAction> {
+        if self.focused == Some(target) {
+            return vec![];
+        }
+        self.focused = Some(target);
+        self.dispatch_event(Event::new(EventType::Focus, target))
+    }
+
+    pub(crate) fn focused(&self) -> Option<NodeId> {
+        self.focused
+    }
+
+    /// What pressing on `target` focuses: the closest link around it.
+    pub(crate) fn focus_target(&self, target: NodeId) -> Option<NodeId> {
+        std::iter::once(target)
+            .chain(self.ancestors(target))
+            .find(|&id| {
+                self.get(id).is_some_and(|element| {
+                    matches!(element.tag(), "a" | "area") && element.has_attribute("href")
+                })
+            })
+    }
+
+    fn invoke_listeners(&mut self, id: NodeId, event: &mut Event, capture: bool) {
+        // Listeners get the DOM, so they're taken out while they run
+        let Some(mut listeners) = self.listeners.remove(&id) else {
+            return;
+        };
+        event.current_target = id;
+        let event_type = event.event_type;
+        for listener in listeners
+            .iter_mut()
+            .filter(|listener| listener.event_type == event_type && listener.capture == capture)
+        {
+            (listener.callback)(event, self);
+        }
+        // Listeners added while dispatching go after the existing ones
+        listeners.extend(self.listeners.remove(&id).unwrap_or_default());
+        self.listeners.insert(id, listeners);
+    }
+
+    fn default_actions(&self, event: &Event) -> Vec<DOMAction> {
+        if event.default_prevented() {
+            return vec![];
+        }
+        let activates = match event.event_type {
+            EventType::Click => true,
+            // Enter on a focused link follows it, like a click would
+            EventType::KeyDown => event.key.as_deref() == Some("Enter"),
+            _ => false,
+        };
+        match self.get(event.target) {
+            Some(element) if activates => element.actions().clone(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use std::{cell::RefCell, rc::Rc};
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    fn page() -> (DOM, NodeId, NodeId, NodeId) {
+        let dom = DOM::construct_dom(parser::parse("<div><p><a href=\"/x\">link</a></p></div>"));
+        let find = |selector| dom.query_selector(selector).unwrap().unwrap();
+        let (div, p, a) = (find("div"), find("p"), find("a"));
+        (dom, div, p, a)
+    }
+
+    /// Adds a listener to `id` that writes `name` and the phase to `log`.
+    fn listen(
+        dom: &mut DOM,
+        id: NodeId,
+        event_type: EventType,
+        capture: bool,
+        name: &str,
+        log: &Log,
+    ) {
+        let (name, log) = (name.to_string(), log.clone());
+        dom.add_event_listener(id, event_type, capture, move |event, _| {
+            log.borrow_mut().push(format!("{name} {:?}", event.phase));
+        });
+    }
+
+    #[test]
+    fn events_go_down_to_the_target_and_back_up() {
+        let (mut dom, div, p, a) = page();
+        let log = Log::default();
+        listen(&mut dom, div, EventType::Click, false, "div", &log);
+        listen(&mut dom, div, EventType::Click, true, "div", &log);
+        listen(&mut dom, p, EventType::Click, true, "p", &log);
+        listen(&mut dom, a, EventType::Click, false, "a", &log);
+        listen(&mut dom, a, EventType::Click, true, "a", &log);
+        listen(&mut dom, p, EventType::MouseDown, true, "p", &log);
+
+        let actions = dom.dispatch_event(Event::new(EventType::Click, a));
+        assert_eq!(
+            *log.borrow(),
+            [
+                "div Capturing",
+                "p Capturing",
+                "a AtTarget",
+                "a AtTarget",
+                "div Bubbling"
+            ]
+        );
+        assert_eq!(actions, [DOMAction::ClickToRedirect("/x".to_string())]);
+    }
+
+    #[test]
+    fn stopping_propagation_still_runs_the_current_node() {
+        let (mut dom, div, p, a) = page();
+        let log = Log::default();
+        dom.add_event_listener(p, EventType::Click, true, |event, _| {
+            event.stop_propagation()
+        });
+        listen(&mut dom, div, EventType::Click, true, "div", &log);
+        listen(&mut dom, p, EventType::Click, true, "p", &log);
+        listen(&mut dom, a, EventType::Click, true, "a", &log);
+        listen(&mut dom, div, EventType::Click, false, "div", &log);
+
+        let actions = dom.dispatch_event(Event::new(EventType::Click, a));
+        assert_eq!(*log.borrow(), ["div Capturing", "p Capturing"]);
+        // Only `prevent_default` cancels the link
+        assert_eq!(actions.len(), 1);
+    }
+
+    #[test]
+    fn preventing_the_default_cancels_the_link() {
+        let (mut dom, div, _, a) = page();
+        dom.add_event_listener(div, EventType::Click, false, |event, _| {
+            event.prevent_default()
+        });
+        assert_eq!(dom.dispatch_event(Event::new(EventType::Click, a)), []);
+
+        // The listener only cancels clicks, so Enter still follows the link
+        let actions = dom.dispatch_event(Event::key_down(a, "Enter"));
+        assert_eq!(actions, [DOMAction::ClickToRedirect("/x".to_string())]);
+        assert_eq!(dom.dispatch_event(Event::key_down(a, "a")), []);
+    }
+
+    #[test]
+    fn focus_is_captured_but_does_not_bubble() {
+        let (mut dom, div, _, a) = page();
+        let log = Log::default();
+        listen(&mut dom, div, EventType::Focus, true, "div", &log);
+        listen(&mut dom, div, EventType::Focus, false, "div", &log);
+        listen(&mut dom, a, EventType::Focus, false, "a", &log);
+
+        assert_eq!(dom.focus(a), []);
+        assert_eq!(dom.focused(), Some(a));
+        assert_eq!(*log.borrow(), ["div Capturing", "a AtTarget"]);
+        // Focusing the focused node again fires nothing
+        dom.focus(a);
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    #[test]
+    fn listeners_added_while_dispatching_run_from_the_next_event() {
+        let (mut dom, _, _, a) = page();
+        let log = Log::default();
+        let added = log.clone();
+        dom.add_event_listener(a, EventType::Click, false, move |_, dom| {
+            listen(dom, a, EventType::Click, false, "added", &added);
+        });
+
+        dom.dispatch_event(Event::new(EventType::Click, a));
+        assert!(log.borrow().is_empty());
+        dom.dispatch_event(Event::new(EventType::Click, a));
+        assert_eq!(*log.borrow(), ["added AtTarget"]);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    collections::BTreeSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The URLs of visited links, kept on disk one per line so `:visited`
+/// styling survives navigation and restarts.
+#[derive(Debug)]
+pub(crate) struct VisitedLinks {
+    path: PathBuf,
+    urls: BTreeSet<String>,
+}
+
+impl VisitedLinks {
+    /// Loads the store at `path`, which doesn't have to exist yet.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let urls = std::fs::read_to_string(&path)
+            .map(|contents| contents.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Self { path, urls }
+    }
+
+    /// Whether a resolved URL was visited.
+    pub(crate) fn contains(&self, url: &str) -> bool {
+        self.urls.contains(url)
+    }
+
+    /// Records a resolved URL as visited.
+    pub(crate) fn visit(&mut self, url: &str) {
+        if url.contains('\n') || !self.urls.insert(url.to_string()) {
+            return;
+        }
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", url));
+        // Losing history isn't worth crashing over
+        if let Err(error) = written {
+            eprintln!(
+                "Couldn't save visited link to {}: {}",
+                self.path.display(),
+                error
+            );
+        }
+    }
+
+    /// Forgets every visited link.
+    pub(crate) fn clear(&mut self) -> std::io::Result<()> {
+        self.urls.clear();
+        match std::fs::remove_file(&self.path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
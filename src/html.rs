@@ -1,11 +1,75 @@
-#[derive(Debug)]
+use crate::serializer::{self, SerializeOptions};
+
+/// Where a node came from in the source document. `start..end` is a byte
+/// range, `line` and `column` locate `start` and are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Elements that never have content or an end tag, including the legacy ones
+/// found on early web pages like `nextid` and `isindex`.
+pub(crate) fn is_void_element(name: &str) -> bool {
+    matches!(
+        name,
+        "area"
+            | "base"
+            | "basefont"
+            | "bgsound"
+            | "br"
+            | "col"
+            | "embed"
+            | "frame"
+            | "hr"
+            | "image"
+            | "img"
+            | "input"
+            | "isindex"
+            | "keygen"
+            | "link"
+            | "meta"
+            | "nextid"
+            | "param"
+            | "source"
+            | "track"
+            | "wbr"
+    )
+}
+
+#[derive(Debug, Clone)]
 pub(crate) enum HTMLElement {
     Element {
+        /// Lowercased tag name, used for every lookup
+        name: String,
+        /// Tag name as spelled in the source
+        #[allow(dead_code, reason = "for tools quoting the source")]
         tag: String,
         attributes: Vec<(String, String)>,
         children: Vec<HTMLElement>,
+        span: Span,
+    },
+    Text {
+        text: String,
+        span: Span,
+    },
+    Comment {
+        text: String,
+        span: Span,
+    },
+    Doctype {
+        /// Everything after `<!DOCTYPE`, like `html`
+        text: String,
+        span: Span,
     },
-    Text(String),
 }
 
 impl HTMLElement {
@@ -13,53 +77,60 @@ impl HTMLElement {
         tag: String,
         attributes: Vec<(String, String)>,
         children: Vec<HTMLElement>,
+        span: Span,
     ) -> Self {
         Self::Element {
+            name: tag.to_ascii_lowercase(),
             tag,
             attributes,
             children,
+            span,
         }
     }
 
-    pub(crate) fn text_node(text: String) -> Self {
-        Self::Text(text)
+    pub(crate) fn text_node(text: String, span: Span) -> Self {
+        Self::Text { text, span }
+    }
+
+    #[allow(dead_code, reason = "for tools mapping nodes back to the source")]
+    pub(crate) fn span(&self) -> Span {
+        match self {
+            HTMLElement::Element { span, .. } => *span,
+            HTMLElement::Text { span, .. } => *span,
+            HTMLElement::Comment { span, .. } => *span,
+            HTMLElement::Doctype { span, .. } => *span,
+        }
     }
 
     pub(crate) fn is_header(&self) -> bool {
         match self {
-            HTMLElement::Element { tag, .. } => {
-                tag == "head" || tag == "HEAD" || tag == "title" || tag == "TITLE"
-            }
+            HTMLElement::Element { name, .. } => name == "head" || name == "title",
             _ => false,
         }
     }
-}
 
-impl ToString for HTMLElement {
-    fn to_string(&self) -> String {
+    /// Text of nothing but ASCII whitespace, which only separates words.
+    pub(crate) fn is_whitespace(&self) -> bool {
         match self {
-            HTMLElement::Element {
-                tag,
-                attributes,
-                children,
-            } => {
-                let mut attributes_str = String::new();
-                for (name, value) in attributes {
-                    attributes_str.push_str(&format!(" {}=\"{}\"", name, value));
-                }
-
-                let mut children_str = String::new();
-                for child in children {
-                    children_str.push_str(&child.to_string());
-                }
+            HTMLElement::Text { text, .. } => text.chars().all(|c| c.is_ascii_whitespace()),
+            _ => false,
+        }
+    }
 
-                if children.is_empty() {
-                    format!("<{}{}/>", tag, attributes_str)
-                } else {
-                    format!("<{}{}>{}</{}>", tag, attributes_str, children_str, tag)
-                }
-            }
-            HTMLElement::Text(s) => s.clone(),
+    /// Nodes whose content is never rendered, like scripts, stylesheets and
+    /// comments.
+    pub(crate) fn is_hidden(&self) -> bool {
+        match self {
+            HTMLElement::Element { name, .. } => name == "script" || name == "style",
+            HTMLElement::Text { .. } => false,
+            HTMLElement::Comment { .. } | HTMLElement::Doctype { .. } => true,
         }
     }
 }
+
+impl std::fmt::Display for HTMLElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let html = serializer::to_html(std::slice::from_ref(self), SerializeOptions::default());
+        write!(f, "{}", html)
+    }
+}
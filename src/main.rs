@@ -1,30 +1,447 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
 
-use dom::DOM;
-use macroquad;
+use css::Stylesheet;
+use dom::{DOMAction, NodeId, DOM};
+use events::{Event, EventType};
+use history::VisitedLinks;
 use macroquad::prelude::*;
-use rendering::render_dom;
+use parser::StreamingParser;
+use rendering::{layout_dom, paint, ElementBox, Layout};
+use reqwest::Url;
 use styling::{FontFamily, FontWeight};
 
+mod cascade;
+mod css;
+mod diagnostics;
 mod dom;
+mod dump;
+mod encoding;
+mod entities;
+mod events;
+mod history;
 mod html;
+mod metadata;
 mod parser;
+mod properties;
 mod rendering;
+mod selector;
+mod serializer;
 mod styling;
+mod traversal;
+mod tree_builder;
 
-#[macroquad::main("Kale")]
-async fn main() {
-    let url = std::env::args().nth(1);
-    let html = if let Some(url) = url {
-        reqwest::blocking::get(&url).unwrap().text().unwrap()
+/// Where the URLs of visited links are kept between runs.
+const VISITED_LINKS_PATH: &str = "visited_links.txt";
+
+/// The reader's style sheet, applied to every page if it exists.
+const USER_STYLESHEET_PATH: &str = "user.css";
+
+/// Where the page shown without a URL argument was saved from, for its
+/// relative links.
+const BUNDLED_PAGE_URL: &str = "http://info.cern.ch/hypertext/WWW/TheProject.html";
+
+fn main() {
+    let mut url = None;
+    let mut lint = false;
+    let mut normalize = false;
+    let mut pretty = false;
+    let mut dump_json = false;
+    let mut text = false;
+    let mut links = false;
+    let mut clear_visited = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--lint" => lint = true,
+            "--normalize" => normalize = true,
+            "--pretty" => pretty = true,
+            "--dump-json" => dump_json = true,
+            "--text" => text = true,
+            "--links" => links = true,
+            "--clear-visited" => clear_visited = true,
+            _ => url = Some(arg),
+        }
+    }
+
+    if clear_visited {
+        if let Err(error) = VisitedLinks::load(VISITED_LINKS_PATH).clear() {
+            eprintln!("Couldn't clear {}: {}", VISITED_LINKS_PATH, error);
+            std::process::exit(2);
+        }
+        return;
+    }
+
+    if !lint && !normalize && !dump_json && !text && !links {
+        let visited = VisitedLinks::load(VISITED_LINKS_PATH);
+        let user_stylesheet = std::fs::read_to_string(USER_STYLESHEET_PATH)
+            .ok()
+            .map(|css| Stylesheet::parse(&css));
+        let page_url = url.clone().unwrap_or(BUNDLED_PAGE_URL.to_string());
+        let page_load = url.map(PageLoad::start);
+        let dom = if page_load.is_some() {
+            DOM::construct_dom(vec![])
+        } else {
+            let html = encoding::decode(include_bytes!("../pages/project2.html"), None);
+            construct_dom(
+                parser::parse(&html),
+                &page_url,
+                &visited,
+                user_stylesheet.as_ref(),
+            )
+        };
+        // The window title can only be set when the window opens, so it only
+        // names the bundled page. `browse` shows the title of whatever page
+        // is loaded in the title bar.
+        let config = macroquad::window::Conf {
+            window_title: page_title(&dom).to_string(),
+            ..Default::default()
+        };
+        macroquad::Window::from_config(
+            config,
+            browse(dom, page_url, page_load, visited, user_stylesheet),
+        );
+        return;
+    }
+
+    let html = if let Some(url) = &url {
+        match fetch(url) {
+            Ok(html) => html,
+            Err(error) => {
+                // Not 1, which `--lint` uses for a page with problems
+                eprintln!("Couldn't load {}: {}", url, error);
+                std::process::exit(2);
+            }
+        }
     } else {
-        include_str!("../pages/project2.html").to_string()
+        encoding::decode(include_bytes!("../pages/project2.html"), None)
     };
 
-    let html_elements = parser::parse(&html).unwrap();
+    if lint {
+        let (_, diagnostics) = parser::parse_with_diagnostics(&html);
+        for diagnostic in &diagnostics {
+            println!("{}", diagnostic);
+        }
+        std::process::exit(if diagnostics.is_empty() { 0 } else { 1 });
+    }
+
+    let html_elements = parser::parse(&html);
+    if dump_json {
+        // Without the visited links and user style sheet, so the output only
+        // depends on the page
+        let dom = DOM::construct_dom(html_elements);
+        println!("{:#}", dump::to_json(&dom));
+        return;
+    }
+
+    if text || links {
+        let dom = DOM::construct_dom(html_elements);
+        if text {
+            for &root in dom.roots() {
+                println!("{}", dom.inner_text(root));
+            }
+        }
+        if links {
+            let page_url = url.as_deref().unwrap_or(BUNDLED_PAGE_URL);
+            let base = base_url(page_url, &dom);
+            for (href, text) in dom.links() {
+                let url = resolve_url(base.as_ref(), &href).unwrap_or(href);
+                println!("{}\t{}", url, text);
+            }
+        }
+        return;
+    }
+
+    let options = serializer::SerializeOptions { pretty };
+    print!("{}", serializer::to_html(&html_elements, options));
+}
+
+/// Downloads a page, returning it decoded to UTF-8.
+fn fetch(url: &str) -> reqwest::Result<String> {
+    let response = reqwest::blocking::get(url)?;
+    let content_type = content_type(&response);
+    let bytes = response.bytes()?;
+    Ok(encoding::decode(&bytes, content_type.as_deref()))
+}
+
+fn content_type(response: &reqwest::blocking::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// What the download thread sends back to the render loop.
+enum DownloadEvent {
+    /// The response started, `url` being where redirects ended up
+    Response {
+        url: String,
+        content_type: Option<String>,
+    },
+    Chunk(Vec<u8>),
+    /// The download stopped, and the channel closes after this
+    Failed(String),
+}
+
+/// A page that is downloaded on a background thread and parsed as its chunks
+/// arrive, so the start of it can be shown before the download finishes.
+struct PageLoad {
+    url: String,
+    events: Receiver<DownloadEvent>,
+    parser: Option<StreamingParser>,
+    /// The page in its original encoding
+    bytes: Vec<u8>,
+    /// Whether the DOM is this page's yet. It has to be built anew when the
+    /// parse starts over.
+    shown: bool,
+}
+
+impl PageLoad {
+    fn start(url: String) -> Self {
+        let (sender, events) = mpsc::channel();
+        let download_url = url.clone();
+        std::thread::spawn(move || {
+            let mut response = match reqwest::blocking::get(&download_url) {
+                Ok(response) => response,
+                Err(error) => {
+                    let _ = sender.send(DownloadEvent::Failed(error.to_string()));
+                    return;
+                }
+            };
+            let event = DownloadEvent::Response {
+                url: response.url().to_string(),
+                content_type: content_type(&response),
+            };
+            if sender.send(event).is_err() {
+                return;
+            }
+            let mut buffer = [0; 4096];
+            // The channel closing tells the render loop the download is done
+            loop {
+                let event = match response.read(&mut buffer) {
+                    Ok(0) => return,
+                    Ok(length) => DownloadEvent::Chunk(buffer[..length].to_vec()),
+                    Err(error) => DownloadEvent::Failed(error.to_string()),
+                };
+                let failed = matches!(event, DownloadEvent::Failed(_));
+                if sender.send(event).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self {
+            url,
+            events,
+            parser: None,
+            bytes: vec![],
+            shown: false,
+        }
+    }
+
+    /// Parses whatever arrived since the last frame. Returns the tree parsed
+    /// so far if it changed, and whether the download is done.
+    fn poll(&mut self) -> (Option<Vec<html::HTMLElement>>, bool) {
+        let mut changed = false;
+        loop {
+            match self.events.try_recv() {
+                Ok(DownloadEvent::Response { url, content_type }) => {
+                    self.url = url;
+                    self.parser = Some(StreamingParser::new(content_type.as_deref()));
+                }
+                Ok(DownloadEvent::Chunk(chunk)) => {
+                    self.bytes.extend_from_slice(&chunk);
+                    if let Some(parser) = &mut self.parser {
+                        changed |= parser.feed(&chunk);
+                        self.shown &= !parser.take_restarted();
+                    }
+                }
+                // Whatever arrived before is still shown
+                Ok(DownloadEvent::Failed(error)) => {
+                    println!("Couldn't load {}: {}", self.url, error);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let elements = self.parser.take().map(|mut parser| {
+                        let (elements, _) = parser.finish();
+                        self.shown &= !parser.take_restarted();
+                        elements
+                    });
+                    return (elements, true);
+                }
+            }
+        }
+        let elements = self
+            .parser
+            .as_ref()
+            .filter(|_| changed)
+            .map(StreamingParser::snapshot);
+        (elements, false)
+    }
+
+    /// Writes the page to `pages/`, in its original encoding.
+    fn save(&self) {
+        if self.bytes.is_empty() {
+            return;
+        }
+        let url = self.url.replace("/", "_");
+        std::fs::write(format!("pages/{}", url), &self.bytes).unwrap();
+    }
+}
+
+/// What the page calls itself, for the window and title bar.
+fn page_title(dom: &DOM) -> &str {
+    dom.metadata
+        .title
+        .as_deref()
+        .filter(|title| !title.is_empty())
+        .unwrap_or("Kale")
+}
+
+/// Height of the bar along the bottom of the window with the page title.
+const TITLE_BAR_HEIGHT: f32 = 24.0;
+
+/// Shows the page title at the bottom of the window, since the window's own
+/// title can't be changed once it's open and pages load after that.
+fn draw_title_bar(title: &str, font: &Font) {
+    let top = screen_height() - TITLE_BAR_HEIGHT;
+    draw_rectangle(0.0, top, screen_width(), TITLE_BAR_HEIGHT, LIGHTGRAY);
+    draw_line(0.0, top, screen_width(), top, 1.0, GRAY);
+    draw_text_ex(
+        title,
+        8.0,
+        top + 17.0,
+        TextParams {
+            font: Some(font),
+            font_size: 16,
+            color: BLACK,
+            ..Default::default()
+        },
+    );
+}
+
+/// What relative links on the page are resolved against: its `<base href>`,
+/// or where the page was loaded from.
+fn base_url(page_url: &str, dom: &DOM) -> Option<Url> {
+    let page_url = Url::parse(page_url).ok()?;
+    let base = dom
+        .metadata
+        .base_url
+        .as_deref()
+        .and_then(|base| page_url.join(base).ok());
+    Some(base.unwrap_or(page_url))
+}
+
+/// Makes a link's `href` absolute, or `None` if it isn't a valid URL.
+fn resolve_url(base: Option<&Url>, href: &str) -> Option<String> {
+    let url = match base {
+        Some(base) => base.join(href),
+        None => Url::parse(href),
+    };
+    url.ok().map(String::from)
+}
+
+/// Marks the links on the page that were visited, for `:visited` styling.
+fn update_visited(dom: &mut DOM, page_url: &str, visited: &VisitedLinks) {
+    let base = base_url(page_url, dom);
+    dom.update_visited(|href| {
+        resolve_url(base.as_ref(), href).is_some_and(|url| visited.contains(&url))
+    });
+}
+
+/// Where a node starts on the page, for scrolling to it. Nodes without a box
+/// of their own use the first box inside them.
+fn scroll_position(dom: &DOM, element_boxes: &[ElementBox], target: NodeId) -> Option<f32> {
+    element_boxes
+        .iter()
+        .filter(|&&(_, _, id)| id == target || dom.ancestors(id).any(|id| id == target))
+        .map(|(bbox, _, _)| bbox.y)
+        .min_by(f32::total_cmp)
+}
+
+/// Saves a link target to `downloads/` on a background thread.
+fn download(url: String, file_name: Option<String>) {
+    // Never let the page pick a path outside of downloads/
+    let file_name = file_name
+        .or_else(|| url.rsplit('/').next().map(str::to_string))
+        .map(|name| name.replace(['/', '\\'], "_"))
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .unwrap_or("download".to_string());
+    println!("Downloading {} to downloads/{}", url, file_name);
+    std::thread::spawn(move || {
+        let saved = reqwest::blocking::get(&url)
+            .and_then(|response| response.bytes())
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| {
+                std::fs::create_dir_all("downloads")
+                    .and_then(|_| std::fs::write(format!("downloads/{}", file_name), bytes))
+                    .map_err(|error| error.to_string())
+            });
+        match saved {
+            Ok(()) => println!("Saved downloads/{}", file_name),
+            Err(error) => println!("Couldn't download {}: {}", url, error),
+        }
+    });
+}
+
+fn construct_dom(
+    html_elements: Vec<html::HTMLElement>,
+    page_url: &str,
+    visited: &VisitedLinks,
+    user_stylesheet: Option<&Stylesheet>,
+) -> DOM {
     let mut dom = DOM::construct_dom(html_elements);
+    dom.set_user_stylesheet(user_stylesheet.cloned());
+    update_visited(&mut dom, page_url, visited);
+    dom
+}
+
+/// Parses what has been downloaded since the last call into `dom`, and drops
+/// the load once it's done. `page_url` becomes the load's once it's shown.
+fn continue_loading(
+    page_load: &mut Option<PageLoad>,
+    dom: &mut DOM,
+    page_url: &mut String,
+    visited: &VisitedLinks,
+    user_stylesheet: Option<&Stylesheet>,
+) {
+    let Some(load) = page_load else {
+        return;
+    };
+    let (html_elements, done) = load.poll();
+    if let Some(html_elements) = html_elements {
+        // The previous page stays up until the new one has something to show
+        if load.shown {
+            dom.update(html_elements);
+        } else {
+            page_url.clone_from(&load.url);
+            *dom = DOM::construct_dom(html_elements);
+            dom.set_user_stylesheet(user_stylesheet.cloned());
+            load.shown = true;
+        }
+        // The page's `<base href>` may have just arrived
+        update_visited(dom, page_url, visited);
+    }
+    if done {
+        load.save();
+        *page_load = None;
+    }
+}
+
+async fn browse(
+    mut dom: DOM,
+    mut page_url: String,
+    mut page_load: Option<PageLoad>,
+    mut visited: VisitedLinks,
+    user_stylesheet: Option<Stylesheet>,
+) {
     let mut fonts = HashMap::new();
     let mut view_port_start = 0.0;
+    // Where the left button went down, a click needs it released there too
+    let mut pressed = None;
+    let mut layout: Option<Layout> = None;
     fonts.insert(
         (FontFamily::TimesNewRoman, FontWeight::Normal),
         load_ttf_font("tnr.ttf").await.unwrap(),
@@ -35,6 +452,14 @@ async fn main() {
     );
 
     loop {
+        continue_loading(
+            &mut page_load,
+            &mut dom,
+            &mut page_url,
+            &visited,
+            user_stylesheet.as_ref(),
+        );
+
         let draw_text = |text: &str, x: f32, y: f32, font_size: u16, color: Color, font: &Font| {
             macroquad::text::draw_text_ex(
                 text,
@@ -42,11 +467,11 @@ async fn main() {
                 view_port_start + y,
                 TextParams {
                     font: Some(font),
-                    font_size: font_size,
+                    font_size,
                     font_scale: 1.0,
                     font_scale_aspect: 1.0,
                     rotation: 0.0,
-                    color: color,
+                    color,
                 },
             )
         };
@@ -62,46 +487,129 @@ async fn main() {
             )
         };
 
-        let element_boxes = render_dom(&dom, &draw_text, &draw_line, &fonts);
+        let width = screen_width();
+        dom.set_viewport_width(width);
+        dom.restyle();
+        // Only lay the page out again when it or the window width changed
+        let dirty = dom.take_layout_dirty();
+        let layout = match &mut layout {
+            Some(layout) if !dirty && layout.width == width => layout,
+            layout => layout.insert(layout_dom(&dom, &fonts, width)),
+        };
+        paint(layout, &draw_text, &draw_line, &fonts);
+        draw_title_bar(
+            page_title(&dom),
+            &fonts[&(FontFamily::TimesNewRoman, FontWeight::Normal)],
+        );
+        let element_boxes = &layout.element_boxes;
         let end_depth = element_boxes
             .iter()
             .map(|(bbox, _, _)| (bbox.y + bbox.height) as i32)
             .max();
-        if macroquad::input::is_mouse_button_down(macroquad::input::MouseButton::Left) {
-            for (bbox, actions, id) in element_boxes.iter() {
-                if bbox.contains(macroquad::input::mouse_position().into()) {
+        // Children come before their parents, so the first box is the innermost
+        let cursor = macroquad::input::mouse_position();
+        let target = element_boxes
+            .iter()
+            .filter(|_| cursor.1 < screen_height() - TITLE_BAR_HEIGHT)
+            .find(|(bbox, _, _)| bbox.contains(cursor.into()))
+            .map(|&(_, _, id)| id);
+
+        let mut actions = vec![];
+        if target != dom.hovered() {
+            dom.set_hovered(target);
+            if let Some(target) = target {
+                actions.extend(dom.dispatch_event(Event::new(EventType::MouseOver, target)));
+            }
+        }
+        if let Some(target) = target {
+            if is_mouse_button_pressed(MouseButton::Left) {
+                pressed = Some(target);
+                actions.extend(dom.dispatch_event(Event::new(EventType::MouseDown, target)));
+                if let Some(focus) = dom.focus_target(target) {
+                    actions.extend(dom.focus(focus));
+                }
+            }
+            if is_mouse_button_released(MouseButton::Left) {
+                actions.extend(dom.dispatch_event(Event::new(EventType::MouseUp, target)));
+                // A click needs the button pressed and released on the same node
+                if pressed == Some(target) {
                     println!(
-                        "Clicked on {id} {} {:?}",
-                        dom.get(&id).unwrap().tag(),
-                        actions
+                        "Clicked on {} {} {:?}",
+                        dom.path(target),
+                        dom.get(target).unwrap().tag(),
+                        dom.get(target).unwrap().actions()
                     );
-                    for action in actions {
-                        println!("{:?}", action);
-                        match action {
-                            dom::DOMAction::ClickToRedirect(url) => {
-                                dom.set_clicked(&id);
-                                // Fetch the new page
-                                let base = "http://info.cern.ch/hypertext/WWW";
-                                let url = url[1..url.len() - 1].to_string();
-                                let url = if url.starts_with("http") {
-                                    url.to_string()
-                                } else if url.starts_with("/") {
-                                    format!("{}{}", base, url)
-                                } else {
-                                    format!("{}/{}", base, url)
-                                };
-
-                                println!("Fetching {}", url);
-
-                                let html = reqwest::blocking::get(&url).unwrap().text().unwrap();
-                                let html_elements = parser::parse(&html).unwrap();
-                                // Write the fetched HTML to a file
-                                let url = url.replace("/", "_");
-                                std::fs::write(format!("pages/{}", url), &html).unwrap();
-
-                                dom = DOM::construct_dom(html_elements);
-                            }
+                    actions.extend(dom.dispatch_event(Event::new(EventType::Click, target)));
+                }
+            }
+        }
+        if is_mouse_button_released(MouseButton::Left) {
+            pressed = None;
+        }
+        if let Some(key) = get_last_key_pressed() {
+            // Without a focused link, keys go to the document
+            if let Some(target) = dom.focused().or(dom.roots().first().copied()) {
+                let key = format!("{:?}", key);
+                actions.extend(dom.dispatch_event(Event::key_down(target, &key)));
+            }
+        }
+
+        let base = base_url(&page_url, &dom);
+        let resolve = |href: &str| {
+            let url = resolve_url(base.as_ref(), href);
+            if url.is_none() {
+                println!("Can't follow the invalid link {}", href);
+            }
+            url
+        };
+        for action in actions {
+            println!("{:?}", action);
+            match action {
+                DOMAction::ClickToRedirect(href) => {
+                    // Fetch the new page
+                    let Some(url) = resolve(&href) else {
+                        continue;
+                    };
+                    visited.visit(&url);
+                    update_visited(&mut dom, &page_url, &visited);
+
+                    println!("Fetching {}", url);
+                    page_load = Some(PageLoad::start(url));
+                }
+                DOMAction::ScrollToFragment(fragment) => {
+                    let top = match dom.fragment_target(&fragment) {
+                        Some(target) => scroll_position(&dom, element_boxes, target),
+                        // `#` and `#top` go to the top unless something has that name
+                        None if fragment.is_empty() || fragment.eq_ignore_ascii_case("top") => {
+                            Some(0.0)
                         }
+                        None => None,
+                    };
+                    if let Some(top) = top {
+                        view_port_start = (-top).max((-end_depth.unwrap_or(0)) as f32);
+                    }
+                }
+                DOMAction::ExternalLink(url) => {
+                    println!("Kale can't open {}, copied it to the clipboard", url);
+                    macroquad::miniquad::window::clipboard_set(&url);
+                }
+                DOMAction::OpenInNewWindow(href) => {
+                    let Some(url) = resolve(&href) else {
+                        continue;
+                    };
+                    visited.visit(&url);
+                    update_visited(&mut dom, &page_url, &visited);
+
+                    println!("Opening {} in a new window", url);
+                    let spawned = std::env::current_exe()
+                        .and_then(|kale| std::process::Command::new(kale).arg(&url).spawn());
+                    if let Err(error) = spawned {
+                        println!("Couldn't open a new window: {}", error);
+                    }
+                }
+                DOMAction::Download { url, file_name } => {
+                    if let Some(url) = resolve(&url) {
+                        download(url, file_name);
                     }
                 }
             }
@@ -111,14 +619,53 @@ async fn main() {
         if scroll != 0.0 {
             view_port_start += scroll * 10.0;
             view_port_start = view_port_start.min(0.0);
-            view_port_start = view_port_start.max((-end_depth.unwrap()) as f32);
-        }
-        // let cursor = macroquad::input::mouse_position();
-        // for (bbox, actions, id) in element_boxes {
-        //     if bbox.contains(cursor.into()) {
-        //         dom.set_hovered(&id);
-        //     }
-        // }
+            // The page may still be empty while it loads
+            view_port_start = view_port_start.max((-end_depth.unwrap_or(0)) as f32);
+        }
         next_frame().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(page_url: &str, html: &str, href: &str) -> Option<String> {
+        let dom = DOM::construct_dom(parser::parse(html));
+        resolve_url(base_url(page_url, &dom).as_ref(), href)
+    }
+
+    #[test]
+    fn links_resolve_against_the_page_url() {
+        let page = "http://example.com/docs/guide/intro.html";
+        let url = |href| resolve(page, "<p>text</p>", href);
+        assert_eq!(
+            url("next.html").unwrap(),
+            "http://example.com/docs/guide/next.html"
+        );
+        assert_eq!(url("../api/").unwrap(), "http://example.com/docs/api/");
+        assert_eq!(url("/index.html").unwrap(), "http://example.com/index.html");
+        assert_eq!(
+            url("//cdn.example.org/a").unwrap(),
+            "http://cdn.example.org/a"
+        );
+        assert_eq!(
+            url("?page=2").unwrap(),
+            "http://example.com/docs/guide/intro.html?page=2"
+        );
+        assert_eq!(url("https://other.org/x").unwrap(), "https://other.org/x");
+        assert_eq!(url("http://[oops"), None);
+    }
+
+    #[test]
+    fn base_href_overrides_the_page_url() {
+        let page = "http://example.com/docs/intro.html";
+        let html = "<head><base href=\"/v2/\"></head><p>text</p>";
+        assert_eq!(
+            resolve(page, html, "next.html").unwrap(),
+            "http://example.com/v2/next.html"
+        );
+        let html = "<head><base href=\"http://mirror.org/\"></head><p>text</p>";
+        assert_eq!(resolve(page, html, "a/b").unwrap(), "http://mirror.org/a/b");
+    }
+}
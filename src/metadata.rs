@@ -0,0 +1,148 @@
+use crate::{encoding, html::HTMLElement};
+
+/// What a document says about itself in its `<head>`, which isn't rendered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct DocumentMetadata {
+    /// The first `<title>`, with whitespace collapsed
+    pub title: Option<String>,
+    /// The first `<base href>`
+    pub base_url: Option<String>,
+    /// The `href`s of `<link rel="stylesheet">`, in document order
+    pub stylesheets: Vec<String>,
+    /// The text of `<style>` elements, in document order
+    pub styles: Vec<String>,
+    pub refresh: Option<Refresh>,
+    /// From `<meta charset>` or `<meta http-equiv="content-type">`
+    pub charset: Option<String>,
+    /// From `<meta name="description">`
+    pub description: Option<String>,
+}
+
+/// A `<meta http-equiv="refresh" content="5; url=next.html">`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Refresh {
+    /// Seconds to wait
+    pub delay: u32,
+    /// Where to go, or reload the page itself if there's none
+    pub url: Option<String>,
+}
+
+impl DocumentMetadata {
+    /// Collects the metadata of a parsed document. Like browsers do, the
+    /// elements are picked up even if they ended up outside of `<head>`.
+    pub(crate) fn from_html(elements: &[HTMLElement]) -> Self {
+        let mut metadata = Self::default();
+        for element in elements {
+            metadata.collect(element);
+        }
+        metadata
+    }
+
+    fn collect(&mut self, element: &HTMLElement) {
+        let HTMLElement::Element {
+            name,
+            attributes,
+            children,
+            ..
+        } = element
+        else {
+            return;
+        };
+        let get = |key: &str| {
+            attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.trim())
+        };
+
+        let text = || -> String {
+            children
+                .iter()
+                .filter_map(|child| match child {
+                    HTMLElement::Text { text, .. } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        match name.as_str() {
+            "title" if self.title.is_none() => {
+                self.title = Some(
+                    text()
+                        .split_ascii_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                );
+            }
+            "style" => self.styles.push(text()),
+            "base" if self.base_url.is_none() => self.base_url = get("href").map(str::to_string),
+            "link" => {
+                let rel = get("rel").unwrap_or_default();
+                let has_rel = |keyword: &str| {
+                    rel.split_ascii_whitespace()
+                        .any(|rel| rel.eq_ignore_ascii_case(keyword))
+                };
+                // Alternate stylesheets only apply when the user picks them
+                let stylesheet = has_rel("stylesheet") && !has_rel("alternate");
+                if let Some(href) = get("href").filter(|_| stylesheet) {
+                    self.stylesheets.push(href.to_string());
+                }
+            }
+            "meta" => {
+                let content = get("content");
+                if let Some(charset) = get("charset") {
+                    self.charset.get_or_insert(charset.to_ascii_lowercase());
+                }
+                match get("http-equiv").map(str::to_ascii_lowercase).as_deref() {
+                    Some("refresh") if self.refresh.is_none() => {
+                        self.refresh = content.and_then(parse_refresh);
+                    }
+                    Some("content-type") if self.charset.is_none() => {
+                        self.charset = content.and_then(encoding::charset_from_content_type);
+                    }
+                    _ => {}
+                }
+                let description =
+                    get("name").is_some_and(|name| name.eq_ignore_ascii_case("description"));
+                if description && self.description.is_none() {
+                    self.description = content.map(str::to_string);
+                }
+            }
+            _ => {}
+        }
+
+        for child in children {
+            self.collect(child);
+        }
+    }
+}
+
+/// Parses the `content` of a refresh, like `5`, `0; url=next.html` or
+/// `3, URL='next.html'`.
+fn parse_refresh(content: &str) -> Option<Refresh> {
+    let digits = content
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(content.len());
+    let delay = content[..digits].parse().ok()?;
+
+    let rest = content[digits..]
+        .trim_start_matches(|c: char| c == '.' || c.is_ascii_digit())
+        .trim_start()
+        .trim_start_matches([';', ','])
+        .trim_start();
+    let rest = match rest.get(..3) {
+        Some(url) if url.eq_ignore_ascii_case("url") => {
+            rest[3..].trim_start().strip_prefix('=').unwrap_or(rest)
+        }
+        _ => rest,
+    };
+    let rest = rest.trim();
+    let url = match rest.strip_prefix(['"', '\'']) {
+        Some(quoted) => quoted.split(['"', '\'']).next().unwrap_or(quoted),
+        None => rest,
+    };
+    Some(Refresh {
+        delay,
+        url: (!url.is_empty()).then(|| url.to_string()),
+    })
+}
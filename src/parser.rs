@@ -1,5 +1,10 @@
-use crate::html::HTMLElement;
-use anyhow::Context;
+use crate::{
+    diagnostics::{DiagnosticKind, ParseDiagnostic},
+    encoding, entities,
+    html::{HTMLElement, Span},
+    tree_builder::TreeBuilder,
+};
+use encoding_rs::{UTF_8, WINDOWS_1252};
 use pest::{iterators::Pair, Parser};
 use pest_derive::Parser;
 
@@ -7,134 +12,666 @@ use pest_derive::Parser;
 #[grammar = "html.pest"]
 pub struct HTMLParser;
 
-pub fn parse(html: &str) -> anyhow::Result<Vec<HTMLElement>> {
-    let pairs = HTMLParser::parse(Rule::html, html).context("Failed to parse HTML")?;
-    let pairs = pairs
-        .into_iter()
-        .next()
-        .context("rule::html doesn't have inner pairs")?
-        .into_inner();
-    let mut elements = Vec::new();
+#[derive(Debug, Clone)]
+pub(crate) enum Token {
+    StartTag {
+        tag: String,
+        attributes: Vec<(String, String)>,
+        self_closing: bool,
+    },
+    EndTag {
+        tag: String,
+    },
+    Text(String),
+    /// The text between `<!--` and `-->`
+    Comment(String),
+    /// Everything after `<!DOCTYPE`, like `html`
+    Doctype(String),
+}
 
-    for pair in pairs {
-        match pair.as_rule() {
-            Rule::element => {
-                elements.push(parse_element(pair).context("Failed to parse element")?);
-            }
-            Rule::doctype => {}
-            e => anyhow::bail!("Unexpected rule: {:?}", e),
+/// How the content of an element is tokenized, see `text_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextMode {
+    /// Everything up to the matching end tag is text, verbatim
+    RawText,
+    /// Like `RawText`, but character references are decoded
+    RcData,
+    /// Everything up to the end of the document is text
+    PlainText,
+}
+
+/// Elements whose content isn't markup. `xmp` and `listing` keep their
+/// HTML 2.0 meaning of literal text.
+pub(crate) fn text_mode(name: &str) -> Option<TextMode> {
+    match name {
+        "script" | "style" | "xmp" | "listing" | "iframe" | "noembed" | "noframes" => {
+            Some(TextMode::RawText)
         }
+        "textarea" | "title" => Some(TextMode::RcData),
+        "plaintext" => Some(TextMode::PlainText),
+        _ => None,
     }
+}
 
-    Ok(elements)
+/// Where a `Tokenizer` is in its input, kept apart so tokenizing can resume
+/// once more input has arrived.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenizerState {
+    position: usize,
+    line: usize,
+    column: usize,
+    /// Set after the start tag of an element from `text_mode`
+    text_mode: Option<(String, TextMode)>,
+    diagnostics: Vec<ParseDiagnostic>,
 }
 
-pub fn parse_element(pair: Pair<Rule>) -> anyhow::Result<HTMLElement> {
-    let element = pair
-        .into_inner()
-        .next()
-        .context("rule::element doesn't have inner element")?;
-    match element.as_rule() {
-        Rule::openCloseTag => parse_open_close_tag(element),
-        Rule::selfClosingTag => parse_self_closing_tag(element),
-        Rule::text => {
-            let text = element.as_str().to_string();
-            Ok(HTMLElement::text_node(text))
+impl Default for TokenizerState {
+    fn default() -> Self {
+        Self {
+            position: 0,
+            line: 1,
+            column: 1,
+            text_mode: None,
+            diagnostics: vec![],
         }
-        e => anyhow::bail!("Unexpected rule: {:?}", e),
     }
 }
 
-struct OpeningTag {
-    tag: String,
-    attributes: Vec<(String, String)>,
+/// Splits an HTML document into tokens, one `Rule::token` match at a time.
+pub(crate) struct Tokenizer<'a> {
+    input: &'a str,
+    /// Whether `input` is the whole document. If not, tokens that more input
+    /// could still extend are held back.
+    complete: bool,
+    state: TokenizerState,
 }
 
-pub fn parse_opening_tag(pair: Pair<Rule>) -> anyhow::Result<OpeningTag> {
-    let mut pair = pair.into_inner();
-    let tag = pair.next().unwrap().as_str().to_string();
-    let mut attributes = Vec::new();
+impl<'a> Tokenizer<'a> {
+    pub(crate) fn new(input: &'a str) -> Self {
+        Self::resume(input, true, TokenizerState::default())
+    }
+
+    /// Continues tokenizing `input`, a longer version of the input `state`
+    /// was taken from.
+    pub(crate) fn resume(input: &'a str, complete: bool, state: TokenizerState) -> Self {
+        Self {
+            input,
+            complete,
+            state,
+        }
+    }
+
+    pub(crate) fn into_state(self) -> TokenizerState {
+        self.state
+    }
+
+    pub(crate) fn take_diagnostics(&mut self) -> Vec<ParseDiagnostic> {
+        std::mem::take(&mut self.state.diagnostics)
+    }
+
+    /// The span of `length` bytes at byte offset `start`, which must not be
+    /// before the current position.
+    fn span(&self, start: usize, length: usize) -> Span {
+        let (mut line, mut column) = (self.state.line, self.state.column);
+        for c in self.input[self.state.position..start].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        Span {
+            start,
+            end: start + length,
+            line,
+            column,
+        }
+    }
+
+    /// Moves past the current token, returning its span.
+    fn advance(&mut self, length: usize) -> Span {
+        let span = self.span(self.state.position, length);
+        let end = self.span(self.state.position + length, 0);
+        self.state.position = end.start;
+        self.state.line = end.line;
+        self.state.column = end.column;
+        span
+    }
+
+    /// Decodes character references in `text`, found at byte offset `start`.
+    fn decode(&mut self, text: &str, start: usize, in_attribute: bool) -> String {
+        let mut unknown = vec![];
+        let decoded = entities::decode_reporting(text, in_attribute, |offset, name| {
+            unknown.push((start + offset, name.to_string()))
+        });
+        for (offset, name) in unknown {
+            let span = self.span(offset, name.len() + 2);
+            self.state.diagnostics.push(ParseDiagnostic::new(
+                DiagnosticKind::UnknownEntity { name },
+                span,
+            ));
+        }
+        decoded
+    }
+
+    /// Consumes the content of a raw text element as a single text token. If
+    /// the end tag hasn't arrived yet, the text mode is kept and nothing is
+    /// returned.
+    fn raw_text(&mut self, name: String, mode: TextMode) -> Option<(Token, Span)> {
+        let rest = &self.input[self.state.position..];
+        let end = match mode {
+            TextMode::PlainText => rest.len(),
+            TextMode::RawText | TextMode::RcData => match find_end_tag(rest, &name) {
+                Some(end) => end,
+                None if self.complete => rest.len(),
+                None => {
+                    self.state.text_mode = Some((name, mode));
+                    return None;
+                }
+            },
+        };
+        if mode == TextMode::PlainText {
+            // There's no end tag, everything that arrives later is text too
+            self.state.text_mode = Some((name, mode));
+        }
+        if end == 0 {
+            return None;
+        }
+
+        let text = &rest[..end];
+        let text = match mode {
+            TextMode::RcData => self.decode(text, self.state.position, false),
+            TextMode::RawText | TextMode::PlainText => text.to_string(),
+        };
+        Some((Token::Text(text), self.advance(end)))
+    }
+
+    fn parse_token(&mut self, pair: Pair<Rule>) -> Token {
+        match pair.as_rule() {
+            Rule::startTag => self.parse_start_tag(pair),
+            Rule::endTag => parse_end_tag(pair),
+            Rule::comment => {
+                let comment = pair.as_str().strip_prefix("<!--").unwrap_or_default();
+                Token::Comment(comment.strip_suffix("-->").unwrap_or(comment).to_string())
+            }
+            // `<?xml ...>` keeps its `?`, like browsers do
+            Rule::bogusComment => {
+                let comment = pair
+                    .as_str()
+                    .strip_prefix("<!")
+                    .unwrap_or(&pair.as_str()[1..]);
+                Token::Comment(comment.strip_suffix('>').unwrap_or(comment).to_string())
+            }
+            Rule::doctype => {
+                let doctype = &pair.as_str()["<!DOCTYPE".len()..pair.as_str().len() - 1];
+                Token::Doctype(doctype.trim().to_string())
+            }
+            Rule::strayLessThan => {
+                let span = self.span(self.state.position, 1);
+                self.state.diagnostics.push(ParseDiagnostic::new(
+                    DiagnosticKind::UnescapedLessThan,
+                    span,
+                ));
+                Token::Text(pair.as_str().to_string())
+            }
+            _ => Token::Text(self.decode(pair.as_str(), self.state.position, false)),
+        }
+    }
+
+    /// Parse <tag attributes> or <tag attributes/>
+    fn parse_start_tag(&mut self, pair: Pair<Rule>) -> Token {
+        let mut tag = String::new();
+        let mut attributes = Vec::new();
+        let mut self_closing = false;
+
+        for pair in pair.into_inner() {
+            match pair.as_rule() {
+                Rule::tagName => tag = pair.as_str().to_string(),
+                Rule::attributes => {
+                    for attribute_pair in pair.into_inner() {
+                        let (name, value) = self.parse_attribute(attribute_pair);
+                        // Duplicate attributes are dropped, the first one wins
+                        if !attributes.iter().any(|(existing, _)| *existing == name) {
+                            attributes.push((name, value));
+                        }
+                    }
+                }
+                Rule::selfClosingFlag => self_closing = true,
+                _ => {}
+            }
+        }
 
-    if let Rule::attributes = pair.peek().unwrap().as_rule() {
-        let attributes_pairs = pair.next().unwrap();
-        for attribute_pair in attributes_pairs.into_inner() {
-            let mut attribute_pair = attribute_pair.into_inner();
-            let attribute_name = attribute_pair.next().unwrap().as_str();
-            let attribute_value = attribute_pair.next().unwrap().as_str();
-            attributes.push((attribute_name.to_string(), attribute_value.to_string()));
+        Token::StartTag {
+            tag,
+            attributes,
+            self_closing,
         }
     }
 
-    Ok(OpeningTag { tag, attributes })
+    /// Parse name, name=value, name="value" or name='value'
+    fn parse_attribute(&mut self, pair: Pair<Rule>) -> (String, String) {
+        let mut pair = pair.into_inner();
+        let name = pair.next().unwrap().as_str().to_ascii_lowercase();
+        let value = pair
+            .next()
+            .and_then(|value| value.into_inner().next())
+            .map(|value| {
+                let start = self.state.position + value.as_span().start();
+                self.decode(value.as_str(), start, true)
+            })
+            .unwrap_or_default();
+        (name, value)
+    }
 }
 
-pub fn parse_closing_tag(pair: Pair<Rule>) -> anyhow::Result<String> {
-    let tag = pair.into_inner().next().unwrap().as_str().to_string();
-    Ok(tag)
+/// Finds `</name` followed by whitespace, `/` or `>`, ignoring case.
+fn find_end_tag(input: &str, name: &str) -> Option<usize> {
+    input.match_indices("</").map(|(i, _)| i).find(|&i| {
+        let after = &input.as_bytes()[i + 2..];
+        after.len() >= name.len()
+            && after[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+            && after
+                .get(name.len())
+                .is_none_or(|c| c.is_ascii_whitespace() || *c == b'/' || *c == b'>')
+    })
 }
 
-pub fn parse_content(pair: Pair<Rule>) -> anyhow::Result<Vec<HTMLElement>> {
-    anyhow::ensure!(pair.as_rule() == Rule::content, "rule is not content");
-    let pairs = pair.into_inner();
-    let mut elements = Vec::new();
+impl Iterator for Tokenizer<'_> {
+    type Item = (Token, Span);
 
-    for element in pairs {
-        match element.as_rule() {
-            Rule::element => {
-                elements.push(parse_element(element).context("Failed to parse element")?);
+    fn next(&mut self) -> Option<(Token, Span)> {
+        if let Some((name, mode)) = self.state.text_mode.take() {
+            if let Some(text) = self.raw_text(name, mode) {
+                return Some(text);
             }
-            Rule::text => {
-                let text = element.as_str().to_string();
-                elements.push(HTMLElement::text_node(text));
+            if self.state.text_mode.is_some() {
+                return None;
+            }
+        }
+
+        let rest = &self.input[self.state.position..];
+        let first = rest.chars().next()?;
+
+        let pair = HTMLParser::parse(Rule::token, rest)
+            .ok()
+            .and_then(|mut pairs| pairs.next())
+            .and_then(|token| token.into_inner().next());
+
+        match pair {
+            Some(pair) => {
+                if !self.complete && !is_complete(&pair, rest) {
+                    return None;
+                }
+                let length = pair.as_str().len();
+                let token = self.parse_token(pair);
+                if let Token::StartTag { tag, .. } = &token {
+                    let name = tag.to_ascii_lowercase();
+                    self.state.text_mode = text_mode(&name).map(|mode| (name, mode));
+                }
+                Some((token, self.advance(length)))
+            }
+            // The grammar accepts any non-empty input, but never get stuck if it doesn't
+            None => Some((
+                Token::Text(first.to_string()),
+                self.advance(first.len_utf8()),
+            )),
+        }
+    }
+}
+
+/// Whether a token matched at the start of `rest` would still be the same
+/// token if more input were appended.
+fn is_complete(pair: &Pair<Rule>, rest: &str) -> bool {
+    let token = pair.as_str();
+    match pair.as_rule() {
+        Rule::text => token.len() < rest.len(),
+        Rule::comment => token.ends_with("-->"),
+        Rule::bogusComment => token.ends_with('>'),
+        // A tag that has only partly arrived doesn't parse yet
+        Rule::strayLessThan => closes_tag(&rest[1..]),
+        // An unquoted value starting with a quote is a quoted one whose
+        // closing quote hasn't arrived yet
+        Rule::startTag => !pair.clone().into_inner().flatten().any(|pair| {
+            pair.as_rule() == Rule::unquotedValue && pair.as_str().starts_with(['"', '\''])
+        }),
+        _ => true,
+    }
+}
+
+/// Whether `rest` has a `>` that isn't inside a quoted attribute value.
+fn closes_tag(mut rest: &str) -> bool {
+    while let Some(index) = rest.find(['>', '=']) {
+        if rest[index..].starts_with('>') {
+            return true;
+        }
+        rest = rest[index + 1..].trim_start_matches(|c: char| c.is_ascii_whitespace());
+        if let Some(quote) = rest.chars().next().filter(|c| matches!(c, '"' | '\'')) {
+            match rest[1..].find(quote) {
+                Some(end) => rest = &rest[end + 2..],
+                None => return false,
             }
-            e => anyhow::bail!("Unexpected rule: {:?}", e),
         }
     }
+    false
+}
 
-    Ok(elements)
+/// Parses a document without ever failing, recovering from malformed markup
+/// the way the tree builder sees fit.
+pub fn parse(html: &str) -> Vec<HTMLElement> {
+    parse_with_diagnostics(html).0
 }
 
-/// Parse <tag attributes>content</tag>
-pub fn parse_open_close_tag(pair: Pair<Rule>) -> anyhow::Result<HTMLElement> {
-    let mut pair = pair.into_inner();
-    let opening_tag = parse_opening_tag(pair.next().unwrap())?;
-    let content = parse_content(pair.next().unwrap())?;
-    // let closing_tag = parse_closing_tag(pair.next().unwrap())?;
+/// Like `parse`, but also returns everything the parser had to recover from,
+/// in document order.
+pub fn parse_with_diagnostics(html: &str) -> (Vec<HTMLElement>, Vec<ParseDiagnostic>) {
+    let mut tokenizer = Tokenizer::new(html);
+    let mut builder = TreeBuilder::default();
+    for (token, span) in tokenizer.by_ref() {
+        builder.process(token, span);
+    }
+
+    let (elements, mut diagnostics) = builder.finish();
+    diagnostics.extend(tokenizer.take_diagnostics());
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    (elements, diagnostics)
+}
+
+/// Parses a document as it downloads. Chunks of bytes go into `feed`, the
+/// tree parsed so far can be looked at with `snapshot` and `finish` returns
+/// the same result `parse_with_diagnostics` would have.
+pub(crate) struct StreamingParser {
+    content_type: Option<String>,
+    /// Bytes held back until there are enough to sniff the encoding
+    undecoded: Vec<u8>,
+    decoder: Option<encoding_rs::Decoder>,
+    /// Every byte so far, while UTF-8 is only a guess that a malformed
+    /// sequence can still prove wrong
+    guessed: Option<Vec<u8>>,
+    /// Set when the parse started over, see `take_restarted`
+    restarted: bool,
+    input: String,
+    tokenizer: TokenizerState,
+    builder: TreeBuilder,
+}
+
+impl StreamingParser {
+    pub(crate) fn new(content_type: Option<&str>) -> Self {
+        Self {
+            content_type: content_type.map(str::to_string),
+            undecoded: vec![],
+            decoder: None,
+            guessed: None,
+            restarted: false,
+            input: String::new(),
+            tokenizer: TokenizerState::default(),
+            builder: TreeBuilder::default(),
+        }
+    }
+
+    /// Parses as much of the document as the bytes received so far allow,
+    /// returning whether the tree changed.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> bool {
+        self.decode(chunk, false);
+        self.tokenize(false)
+    }
+
+    /// The tree parsed so far, with open elements closed where the input ends.
+    pub(crate) fn snapshot(&self) -> Vec<HTMLElement> {
+        self.builder.snapshot()
+    }
+
+    pub(crate) fn finish(&mut self) -> (Vec<HTMLElement>, Vec<ParseDiagnostic>) {
+        self.decode(&[], true);
+        self.tokenize(true);
+
+        let (elements, mut diagnostics) = std::mem::take(&mut self.builder).finish();
+        diagnostics.append(&mut self.tokenizer.diagnostics);
+        diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+        (elements, diagnostics)
+    }
+
+    /// Whether the parse started over from the first byte since the last
+    /// call, which makes trees built from earlier snapshots out of date.
+    pub(crate) fn take_restarted(&mut self) -> bool {
+        std::mem::take(&mut self.restarted)
+    }
+
+    fn decode(&mut self, chunk: &[u8], last: bool) {
+        let Some(decoder) = &mut self.decoder else {
+            self.undecoded.extend_from_slice(chunk);
+            if self.undecoded.len() < encoding::PRESCAN_LENGTH && !last {
+                return;
+            }
+            let content_type = self.content_type.as_deref();
+            let encoding = encoding::sniff(&self.undecoded, content_type, last);
+            if encoding == UTF_8 && encoding::declared(&self.undecoded, content_type).is_none() {
+                self.guessed = Some(vec![]);
+            }
+            self.decoder = Some(encoding.new_decoder());
+            let undecoded = std::mem::take(&mut self.undecoded);
+            return self.decode(&undecoded, last);
+        };
+        if let Some(bytes) = &mut self.guessed {
+            bytes.extend_from_slice(chunk);
+        }
+        let malformed = encoding::decode_chunk(decoder, chunk, &mut self.input, last);
+        if let Some(bytes) = self.guessed.take_if(|_| malformed) {
+            self.restart(&bytes, last);
+        }
+    }
+
+    /// Starts over, decoding everything as windows-1252, once a document
+    /// guessed to be UTF-8 turns out not to be.
+    fn restart(&mut self, bytes: &[u8], last: bool) {
+        let mut decoder = WINDOWS_1252.new_decoder();
+        self.input.clear();
+        encoding::decode_chunk(&mut decoder, bytes, &mut self.input, last);
+        self.decoder = Some(decoder);
+        self.tokenizer = TokenizerState::default();
+        self.builder = TreeBuilder::default();
+        self.restarted = true;
+    }
 
-    // anyhow::ensure!(
-    //     opening_tag.tag == closing_tag,
-    //     "open tag and close tag doesn't match"
-    // );
+    fn tokenize(&mut self, complete: bool) -> bool {
+        let state = std::mem::take(&mut self.tokenizer);
+        let mut tokenizer = Tokenizer::resume(&self.input, complete, state);
+        let mut changed = false;
+        for (token, span) in tokenizer.by_ref() {
+            self.builder.process(token, span);
+            changed = true;
+        }
+        self.tokenizer = tokenizer.into_state();
+        changed
+    }
+}
 
-    Ok(HTMLElement::element(
-        opening_tag.tag,
-        opening_tag.attributes,
-        content,
-    ))
+/// Parse </tag>
+fn parse_end_tag(pair: Pair<Rule>) -> Token {
+    let tag = pair.into_inner().next().unwrap().as_str().to_string();
+    Token::EndTag { tag }
 }
 
-pub fn parse_self_closing_tag(pair: Pair<Rule>) -> anyhow::Result<HTMLElement> {
-    let mut pair = pair.into_inner();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(html: &str) -> Vec<(String, String)> {
+        match Tokenizer::new(html).next() {
+            Some((Token::StartTag { attributes, .. }, _)) => attributes,
+            token => panic!("expected a start tag, got {:?}", token),
+        }
+    }
+
+    fn pairs(attributes: &[(&str, &str)]) -> Vec<(String, String)> {
+        attributes
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The text inside the first `name` element.
+    fn text_in(elements: &[HTMLElement], name: &str) -> Option<String> {
+        elements.iter().find_map(|element| match element {
+            HTMLElement::Element {
+                name: element_name,
+                children,
+                ..
+            } if element_name == name => Some(
+                children
+                    .iter()
+                    .map(|child| match child {
+                        HTMLElement::Text { text, .. } => text.as_str(),
+                        _ => panic!("<{}> has more than text in it", name),
+                    })
+                    .collect(),
+            ),
+            HTMLElement::Element { children, .. } => text_in(children, name),
+            _ => None,
+        })
+    }
 
-    if let Rule::br = pair.peek().unwrap().as_rule() {
-        return Ok(HTMLElement::element("br".to_string(), vec![], vec![]));
+    #[test]
+    fn attributes_may_be_valueless_unquoted_or_quoted() {
+        assert_eq!(
+            attributes("<input disabled value=a/b.html?c=1 name='x y' title=\"'z'\">"),
+            pairs(&[
+                ("disabled", ""),
+                ("value", "a/b.html?c=1"),
+                ("name", "x y"),
+                ("title", "'z'"),
+            ])
+        );
+        assert_eq!(attributes("<dl compact>"), pairs(&[("compact", "")]));
+        assert_eq!(
+            attributes("<a HREF=foo.html>"),
+            pairs(&[("href", "foo.html")])
+        );
     }
 
-    if let Rule::meta = pair.peek().unwrap().as_rule() {
-        return Ok(HTMLElement::element("meta".to_string(), vec![], vec![]));
+    #[test]
+    fn the_first_of_duplicate_attributes_wins() {
+        assert_eq!(
+            attributes("<a href=first.html Href='second.html' id=a HREF>"),
+            pairs(&[("href", "first.html"), ("id", "a")])
+        );
     }
 
-    let tag = pair.next().unwrap().as_str().to_string();
-    let mut attributes = Vec::new();
+    #[test]
+    fn raw_text_elements_keep_markup_and_references_verbatim() {
+        let html = "<script>if (a < b && c) { x = \"</p>&amp;\"; }</script><p>after";
+        let elements = parse(html);
+        assert_eq!(
+            text_in(&elements, "script").unwrap(),
+            "if (a < b && c) { x = \"</p>&amp;\"; }"
+        );
+        assert_eq!(text_in(&elements, "p").unwrap(), "after");
+        let elements = parse("<style>a > b { content: \"<i>\" }</STYLE ><xmp><b>&lt;</b></xmp>");
+        assert_eq!(
+            text_in(&elements, "style").unwrap(),
+            "a > b { content: \"<i>\" }"
+        );
+        assert_eq!(text_in(&elements, "xmp").unwrap(), "<b>&lt;</b>");
+    }
 
-    if let Rule::attributes = pair.peek().unwrap().as_rule() {
-        let attributes_pairs = pair.next().unwrap();
-        for attribute_pair in attributes_pairs.into_inner() {
-            let mut attribute_pair = attribute_pair.into_inner();
-            let attribute_name = attribute_pair.next().unwrap().as_str();
-            let attribute_value = attribute_pair.next().unwrap().as_str();
-            attributes.push((attribute_name.to_string(), attribute_value.to_string()));
+    #[test]
+    fn rcdata_elements_decode_references_but_not_markup() {
+        let elements = parse("<title>A &amp; B <b>C</b></title><textarea>&lt;p&gt; <p></textarea>");
+        assert_eq!(text_in(&elements, "title").unwrap(), "A & B <b>C</b>");
+        assert_eq!(text_in(&elements, "textarea").unwrap(), "<p> <p>");
+    }
+
+    #[test]
+    fn plaintext_runs_to_the_end_of_the_document() {
+        let elements = parse("<plaintext><b>a</b></plaintext>&amp;");
+        assert_eq!(
+            text_in(&elements, "plaintext").unwrap(),
+            "<b>a</b></plaintext>&amp;"
+        );
+    }
+
+    #[test]
+    fn diagnostics_have_kinds_and_positions() {
+        let html = "<div>\n  <p>a &bogus; b</span>\n  <b>x < y</div>\n<span>z";
+        let (_, diagnostics) = parse_with_diagnostics(html);
+        let kinds: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.kind.clone())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                DiagnosticKind::UnknownEntity {
+                    name: "bogus".to_string()
+                },
+                DiagnosticKind::StrayEndTag {
+                    tag: "span".to_string()
+                },
+                DiagnosticKind::UnescapedLessThan,
+                DiagnosticKind::MismatchedEndTag {
+                    expected: "b".to_string(),
+                    found: "div".to_string()
+                },
+                DiagnosticKind::MissingCloseTag {
+                    tag: "span".to_string()
+                },
+            ]
+        );
+        let positions: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.span.to_string())
+            .collect();
+        assert_eq!(positions, ["2:8", "2:17", "3:8", "3:11", "4:1"]);
+        assert_eq!(
+            &html[diagnostics[0].span.start..diagnostics[0].span.end],
+            "&bogus;"
+        );
+        assert_eq!(
+            &html[diagnostics[1].span.start..diagnostics[1].span.end],
+            "</span>"
+        );
+    }
+
+    #[test]
+    fn elements_know_where_they_came_from() {
+        let html = "<div>\n  <p>a</p>\n</div>";
+        let elements = parse(html);
+        let HTMLElement::Element { children, span, .. } = &elements[0] else {
+            panic!("expected a <div>");
+        };
+        assert_eq!(
+            (span.start, span.end, span.to_string()),
+            (0, html.len(), "1:1".to_string())
+        );
+        let paragraph = children[1].span();
+        assert_eq!(&html[paragraph.start..paragraph.end], "<p>a</p>");
+        assert_eq!((paragraph.line, paragraph.column), (2, 3));
+    }
+
+    #[test]
+    fn streaming_starts_over_when_a_utf_8_guess_turns_out_wrong() {
+        let mut page =
+            format!("<p>{}</p><p>", "a".repeat(2 * encoding::PRESCAN_LENGTH)).into_bytes();
+        page.extend_from_slice(b"caf\xe9 cr\xe8me</p>");
+        let mut parser = StreamingParser::new(Some("text/html"));
+        let mut restarted = false;
+        for chunk in page.chunks(100) {
+            parser.feed(chunk);
+            restarted |= parser.take_restarted();
         }
+        assert!(restarted);
+        let (elements, _) = parser.finish();
+        let expected = parse(&encoding::decode(&page, Some("text/html")));
+        assert_eq!(text_in(&elements[1..], "p").unwrap(), "café crème");
+        assert_eq!(format!("{:?}", elements), format!("{:?}", expected));
+
+        // A declared encoding is never taken back
+        let mut parser = StreamingParser::new(Some("text/html; charset=utf-8"));
+        parser.feed(&page);
+        assert!(!parser.take_restarted());
+        assert_eq!(
+            text_in(&parser.finish().0[1..], "p").unwrap(),
+            "caf\u{fffd} cr\u{fffd}me"
+        );
     }
-    Ok(HTMLElement::element(tag, attributes, vec![]))
 }
@@ -0,0 +1,613 @@
+use crate::{
+    css::Token,
+    styling::{
+        Color, Display, FontFamily, FontStyle, FontWeight, Style, TextDecorationLine,
+        TextDecorationStyle, Unit,
+    },
+};
+
+/// The value a declaration gives one of the `Style` fields. Shorthands are
+/// expanded into their longhands.
+#[derive(Debug, Clone)]
+pub(crate) enum PropertyDeclaration {
+    Display(Display),
+    MarginTop(Unit),
+    MarginRight(Unit),
+    MarginBottom(Unit),
+    MarginLeft(Unit),
+    FontSize(Unit),
+    FontFamily(FontFamily),
+    FontWeight(FontWeight),
+    FontStyle(FontStyle),
+    Color(CSSColor),
+    TextDecorationLine(TextDecorationLine),
+    TextDecorationStyle(TextDecorationStyle),
+    TextDecorationColor(CSSColor),
+    /// `inherit`, `initial` or `unset`, which any property can be set to
+    CSSWide(LonghandId, CSSWideKeyword),
+}
+
+/// The properties that set one of the `Style` fields each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LonghandId {
+    Display,
+    MarginTop,
+    MarginRight,
+    MarginBottom,
+    MarginLeft,
+    FontSize,
+    FontFamily,
+    FontWeight,
+    FontStyle,
+    Color,
+    TextDecorationLine,
+    TextDecorationStyle,
+    TextDecorationColor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CSSWideKeyword {
+    /// The parent's value
+    Inherit,
+    /// The value the property has when nothing sets it
+    Initial,
+    /// `inherit` for inherited properties, `initial` for the others
+    Unset,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CSSColor {
+    Rgba(Color),
+    /// The value of `color`, or of the parent's for `color` itself
+    CurrentColor,
+}
+
+/// A token of a value, with functions like `rgb(1, 2, 3)` kept together.
+/// Whitespace is dropped.
+enum Component<'a> {
+    Token(&'a Token),
+    Function(&'a str, Vec<&'a Token>),
+}
+
+fn components(value: &[Token]) -> Vec<Component<'_>> {
+    let mut components = vec![];
+    let mut tokens = value.iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Whitespace => {}
+            Token::Function(name) => {
+                let arguments = tokens
+                    .by_ref()
+                    .take_while(|token| **token != Token::CloseParen)
+                    .filter(|token| **token != Token::Whitespace)
+                    .collect();
+                components.push(Component::Function(name, arguments));
+            }
+            token => components.push(Component::Token(token)),
+        }
+    }
+    components
+}
+
+impl LonghandId {
+    pub(crate) const ALL: [Self; 13] = [
+        Self::Display,
+        Self::MarginTop,
+        Self::MarginRight,
+        Self::MarginBottom,
+        Self::MarginLeft,
+        Self::FontSize,
+        Self::FontFamily,
+        Self::FontWeight,
+        Self::FontStyle,
+        Self::Color,
+        Self::TextDecorationLine,
+        Self::TextDecorationStyle,
+        Self::TextDecorationColor,
+    ];
+
+    /// The longhands a property sets, several for shorthands.
+    fn from_name(name: &str) -> Option<&'static [Self]> {
+        let longhands: &[Self] = match name {
+            "display" => &[Self::Display],
+            "margin" => &[
+                Self::MarginTop,
+                Self::MarginRight,
+                Self::MarginBottom,
+                Self::MarginLeft,
+            ],
+            "margin-top" => &[Self::MarginTop],
+            "margin-right" => &[Self::MarginRight],
+            "margin-bottom" => &[Self::MarginBottom],
+            "margin-left" => &[Self::MarginLeft],
+            "font" => &[
+                Self::FontStyle,
+                Self::FontWeight,
+                Self::FontSize,
+                Self::FontFamily,
+            ],
+            "font-size" => &[Self::FontSize],
+            "font-family" => &[Self::FontFamily],
+            "font-weight" => &[Self::FontWeight],
+            "font-style" => &[Self::FontStyle],
+            "color" => &[Self::Color],
+            "text-decoration" => &[
+                Self::TextDecorationLine,
+                Self::TextDecorationStyle,
+                Self::TextDecorationColor,
+            ],
+            "text-decoration-line" => &[Self::TextDecorationLine],
+            "text-decoration-style" => &[Self::TextDecorationStyle],
+            "text-decoration-color" => &[Self::TextDecorationColor],
+            _ => return None,
+        };
+        Some(longhands)
+    }
+
+    /// Whether an element gets the parent's value when nothing sets it.
+    /// Text decorations aren't inherited in CSS, but Kale draws them by
+    /// passing them down to the text instead.
+    pub(crate) fn inherited(self) -> bool {
+        !matches!(
+            self,
+            Self::Display
+                | Self::MarginTop
+                | Self::MarginRight
+                | Self::MarginBottom
+                | Self::MarginLeft
+        )
+    }
+
+    /// Copies the value of this property from one style to another.
+    pub(crate) fn copy(self, from: &Style, to: &mut Style) {
+        match self {
+            Self::Display => to.display = from.display,
+            Self::MarginTop => to.margin.top = from.margin.top,
+            Self::MarginRight => to.margin.right = from.margin.right,
+            Self::MarginBottom => to.margin.bottom = from.margin.bottom,
+            Self::MarginLeft => to.margin.left = from.margin.left,
+            Self::FontSize => to.font.size = from.font.size,
+            Self::FontFamily => to.font.family = from.font.family,
+            Self::FontWeight => to.font.weight = from.font.weight,
+            Self::FontStyle => to.font.style = from.font.style,
+            Self::Color => to.color = from.color,
+            Self::TextDecorationLine => to.text_decoration.line = from.text_decoration.line.clone(),
+            Self::TextDecorationStyle => {
+                to.text_decoration.style = from.text_decoration.style.clone();
+            }
+            Self::TextDecorationColor => to.text_decoration.color = from.text_decoration.color,
+        }
+    }
+}
+
+impl PropertyDeclaration {
+    /// Parses the value of a property. Returns `None` for unsupported
+    /// properties and invalid values, which are ignored.
+    pub(crate) fn parse(name: &str, value: &[Token]) -> Option<Vec<Self>> {
+        let components = components(value);
+        let single = || match components.as_slice() {
+            [component] => Some(component),
+            _ => None,
+        };
+        let css_wide = match single().and_then(keyword).as_deref() {
+            Some("inherit") => Some(CSSWideKeyword::Inherit),
+            Some("initial") => Some(CSSWideKeyword::Initial),
+            Some("unset") => Some(CSSWideKeyword::Unset),
+            _ => None,
+        };
+        if let Some(css_wide) = css_wide {
+            let longhands = LonghandId::from_name(name)?;
+            return Some(
+                longhands
+                    .iter()
+                    .map(|&id| Self::CSSWide(id, css_wide))
+                    .collect(),
+            );
+        }
+        let declaration = match name {
+            "display" => Self::Display(display(keyword(single()?)?.as_str())?),
+            "margin" => return margin(&components),
+            "margin-top" => Self::MarginTop(margin_width(single()?)?),
+            "margin-right" => Self::MarginRight(margin_width(single()?)?),
+            "margin-bottom" => Self::MarginBottom(margin_width(single()?)?),
+            "margin-left" => Self::MarginLeft(margin_width(single()?)?),
+            "font" => return font(&components),
+            "font-size" => Self::FontSize(font_size(single()?)?),
+            "font-family" => Self::FontFamily(font_family(&components)?),
+            "font-weight" => Self::FontWeight(font_weight(single()?)?),
+            "font-style" => Self::FontStyle(font_style(single()?)?),
+            "color" => Self::Color(color(single()?)?),
+            "text-decoration" => return text_decoration(&components),
+            "text-decoration-line" => Self::TextDecorationLine(text_decoration_line(&components)?),
+            "text-decoration-style" => Self::TextDecorationStyle(text_decoration_style(single()?)?),
+            "text-decoration-color" => Self::TextDecorationColor(color(single()?)?),
+            _ => return None,
+        };
+        Some(vec![declaration])
+    }
+
+    pub(crate) fn id(&self) -> LonghandId {
+        match self {
+            Self::Display(_) => LonghandId::Display,
+            Self::MarginTop(_) => LonghandId::MarginTop,
+            Self::MarginRight(_) => LonghandId::MarginRight,
+            Self::MarginBottom(_) => LonghandId::MarginBottom,
+            Self::MarginLeft(_) => LonghandId::MarginLeft,
+            Self::FontSize(_) => LonghandId::FontSize,
+            Self::FontFamily(_) => LonghandId::FontFamily,
+            Self::FontWeight(_) => LonghandId::FontWeight,
+            Self::FontStyle(_) => LonghandId::FontStyle,
+            Self::Color(_) => LonghandId::Color,
+            Self::TextDecorationLine(_) => LonghandId::TextDecorationLine,
+            Self::TextDecorationStyle(_) => LonghandId::TextDecorationStyle,
+            Self::TextDecorationColor(_) => LonghandId::TextDecorationColor,
+            Self::CSSWide(id, _) => *id,
+        }
+    }
+
+    /// Sets the field of `style` this declaration is for, as declared.
+    /// `currentcolor` is resolved against `parent_color` for `color` and
+    /// against the color of `style` otherwise. CSS-wide keywords are left
+    /// to the cascade.
+    pub(crate) fn apply(&self, style: &mut Style, parent_color: Color) {
+        let resolve = |color: CSSColor, current: Color| match color {
+            CSSColor::Rgba(color) => color,
+            CSSColor::CurrentColor => current,
+        };
+        match self {
+            Self::Display(display) => style.display = *display,
+            Self::MarginTop(unit) => style.margin.top = *unit,
+            Self::MarginRight(unit) => style.margin.right = *unit,
+            Self::MarginBottom(unit) => style.margin.bottom = *unit,
+            Self::MarginLeft(unit) => style.margin.left = *unit,
+            Self::FontSize(unit) => style.font.size = *unit,
+            Self::FontFamily(family) => style.font.family = *family,
+            Self::FontWeight(weight) => style.font.weight = *weight,
+            Self::FontStyle(font_style) => style.font.style = *font_style,
+            Self::Color(color) => style.color = resolve(*color, parent_color),
+            Self::TextDecorationLine(line) => style.text_decoration.line = line.clone(),
+            Self::TextDecorationStyle(decoration_style) => {
+                style.text_decoration.style = decoration_style.clone();
+            }
+            Self::TextDecorationColor(color) => {
+                style.text_decoration.color = resolve(*color, style.color);
+            }
+            Self::CSSWide(..) => {}
+        }
+    }
+}
+
+/// A lowercased identifier.
+fn keyword(component: &Component) -> Option<String> {
+    match component {
+        Component::Token(Token::Ident(ident)) => Some(ident.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// Kale only lays out blocks and inline text, so other display types use
+/// the one their box acts as among its siblings. `none` isn't supported.
+fn display(keyword: &str) -> Option<Display> {
+    match keyword {
+        "block" | "list-item" | "flex" | "grid" | "table" | "flow-root" => Some(Display::Block),
+        // Without table layout, rows and captions are lines and cells run
+        // along them
+        "table-caption" | "table-header-group" | "table-row-group" | "table-footer-group"
+        | "table-row" => Some(Display::Block),
+        "inline" | "inline-block" | "inline-flex" | "inline-grid" | "inline-table"
+        | "table-cell" => Some(Display::Inline),
+        _ => None,
+    }
+}
+
+fn length(component: &Component) -> Option<Unit> {
+    match component {
+        Component::Token(Token::Number(number)) if *number == 0.0 => Some(Unit::Px(0.0)),
+        Component::Token(Token::Dimension(number, unit)) => {
+            match unit.to_ascii_lowercase().as_str() {
+                "px" => Some(Unit::Px(*number)),
+                "em" => Some(Unit::Em(*number)),
+                "rem" => Some(Unit::Rem(*number)),
+                "pt" => Some(Unit::Px(number * 4.0 / 3.0)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A length, or `auto`, which is 0 since blocks are never centered.
+fn margin_width(component: &Component) -> Option<Unit> {
+    match keyword(component).as_deref() {
+        Some("auto") => Some(Unit::Px(0.0)),
+        _ => length(component),
+    }
+}
+
+/// `margin: top [right [bottom [left]]]`, with missing sides copied from
+/// the opposite one.
+fn margin(components: &[Component]) -> Option<Vec<PropertyDeclaration>> {
+    let widths = components
+        .iter()
+        .map(margin_width)
+        .collect::<Option<Vec<_>>>()?;
+    let [top, right, bottom, left] = match *widths.as_slice() {
+        [all] => [all, all, all, all],
+        [vertical, horizontal] => [vertical, horizontal, vertical, horizontal],
+        [top, horizontal, bottom] => [top, horizontal, bottom, horizontal],
+        [top, right, bottom, left] => [top, right, bottom, left],
+        _ => return None,
+    };
+    Some(vec![
+        PropertyDeclaration::MarginTop(top),
+        PropertyDeclaration::MarginRight(right),
+        PropertyDeclaration::MarginBottom(bottom),
+        PropertyDeclaration::MarginLeft(left),
+    ])
+}
+
+fn font_size(component: &Component) -> Option<Unit> {
+    match component {
+        Component::Token(Token::Percentage(percentage)) if *percentage >= 0.0 => {
+            return Some(Unit::Em(percentage / 100.0));
+        }
+        Component::Token(Token::Dimension(number, _)) if *number >= 0.0 => {
+            return length(component);
+        }
+        _ => {}
+    }
+    let size = match keyword(component)?.as_str() {
+        "xx-small" => Unit::Px(9.0),
+        "x-small" => Unit::Px(10.0),
+        "small" => Unit::Px(13.0),
+        "medium" => Unit::Px(16.0),
+        "large" => Unit::Px(18.0),
+        "x-large" => Unit::Px(24.0),
+        "xx-large" => Unit::Px(32.0),
+        "xxx-large" => Unit::Px(48.0),
+        "smaller" => Unit::Em(1.0 / 1.2),
+        "larger" => Unit::Em(1.2),
+        _ => return None,
+    };
+    Some(size)
+}
+
+/// The first family in the list that Kale has a font for. Any other family
+/// falls back to the default font, like it would in a browser without it.
+fn font_family(components: &[Component]) -> Option<FontFamily> {
+    let mut families = vec![String::new()];
+    for component in components {
+        match component {
+            Component::Token(Token::Comma) => families.push(String::new()),
+            Component::Token(Token::String(name)) => families.last_mut()?.push_str(name),
+            // Unquoted names can be several identifiers
+            Component::Token(Token::Ident(name)) => {
+                let family = families.last_mut()?;
+                if !family.is_empty() {
+                    family.push(' ');
+                }
+                family.push_str(name);
+            }
+            _ => return None,
+        }
+    }
+    if families.iter().any(String::is_empty) {
+        return None;
+    }
+    let family = families
+        .iter()
+        .find_map(|family| match family.to_ascii_lowercase().as_str() {
+            "arial" | "helvetica" | "sans-serif" => Some(FontFamily::Arial),
+            "times new roman" | "times" | "serif" => Some(FontFamily::TimesNewRoman),
+            _ => None,
+        });
+    Some(family.unwrap_or_default())
+}
+
+fn font_weight(component: &Component) -> Option<FontWeight> {
+    if let Component::Token(Token::Number(weight)) = component {
+        return (1.0..=1000.0)
+            .contains(weight)
+            .then_some(if *weight >= 600.0 {
+                FontWeight::Bold
+            } else {
+                FontWeight::Normal
+            });
+    }
+    match keyword(component)?.as_str() {
+        "normal" | "lighter" => Some(FontWeight::Normal),
+        "bold" | "bolder" => Some(FontWeight::Bold),
+        _ => None,
+    }
+}
+
+fn font_style(component: &Component) -> Option<FontStyle> {
+    match keyword(component)?.as_str() {
+        "normal" => Some(FontStyle::Normal),
+        "italic" | "oblique" => Some(FontStyle::Italic),
+        _ => None,
+    }
+}
+
+/// `font: [style] [weight] size[/line-height] family`. The line height
+/// isn't supported and is ignored.
+fn font(components: &[Component]) -> Option<Vec<PropertyDeclaration>> {
+    let mut font_style_value = FontStyle::Normal;
+    let mut weight = FontWeight::Normal;
+    let mut index = 0;
+    let size = loop {
+        let component = components.get(index)?;
+        index += 1;
+        if keyword(component).as_deref() == Some("normal") {
+            continue;
+        }
+        if let Some(value) = font_style(component) {
+            font_style_value = value;
+        } else if let Some(value) = font_weight(component) {
+            weight = value;
+        } else {
+            break font_size(component)?;
+        }
+    };
+    if let Some(Component::Token(Token::Delim('/'))) = components.get(index) {
+        index += 2;
+    }
+    let family = font_family(components.get(index..)?)?;
+    Some(vec![
+        PropertyDeclaration::FontStyle(font_style_value),
+        PropertyDeclaration::FontWeight(weight),
+        PropertyDeclaration::FontSize(size),
+        PropertyDeclaration::FontFamily(family),
+    ])
+}
+
+fn color(component: &Component) -> Option<CSSColor> {
+    match component {
+        Component::Token(Token::Hash(hex)) => hex_color(hex).map(CSSColor::Rgba),
+        Component::Token(Token::Ident(name)) => match name.to_ascii_lowercase().as_str() {
+            "currentcolor" => Some(CSSColor::CurrentColor),
+            name => named_color(name).map(CSSColor::Rgba),
+        },
+        Component::Function(name, arguments)
+            if name.eq_ignore_ascii_case("rgb") || name.eq_ignore_ascii_case("rgba") =>
+        {
+            rgb_function(arguments).map(CSSColor::Rgba)
+        }
+        _ => None,
+    }
+}
+
+/// `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`.
+fn hex_color(hex: &str) -> Option<Color> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digits: Vec<u8> = match hex.len() {
+        3 | 4 => hex
+            .chars()
+            .map(|c| u8::from_str_radix(&c.to_string().repeat(2), 16))
+            .collect::<Result<_, _>>()
+            .ok()?,
+        6 | 8 => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .ok()?,
+        _ => return None,
+    };
+    let color = Color::new(digits[0], digits[1], digits[2]);
+    Some(match digits.get(3) {
+        Some(&alpha) => color.with_alpha(alpha),
+        None => color,
+    })
+}
+
+/// `rgb(r, g, b)`, `rgba(r, g, b, a)` or `rgb(r g b / a)`, with numbers or
+/// percentages.
+fn rgb_function(arguments: &[&Token]) -> Option<Color> {
+    let values: Vec<_> = arguments
+        .iter()
+        .filter(|token| !matches!(token, Token::Comma | Token::Delim('/')))
+        .collect();
+    let channel = |token: &Token, max: f32| match token {
+        Token::Number(number) => Some(number.clamp(0.0, max) / max * 255.0),
+        Token::Percentage(percentage) => Some(percentage.clamp(0.0, 100.0) / 100.0 * 255.0),
+        _ => None,
+    };
+    let (r, g, b, a) = match values.as_slice() {
+        [r, g, b] => (r, g, b, None),
+        [r, g, b, a] => (r, g, b, Some(a)),
+        _ => return None,
+    };
+    let color = Color::new(
+        channel(r, 255.0)?.round() as u8,
+        channel(g, 255.0)?.round() as u8,
+        channel(b, 255.0)?.round() as u8,
+    );
+    Some(match a {
+        Some(a) => color.with_alpha(channel(a, 1.0)?.round() as u8),
+        None => color,
+    })
+}
+
+/// The named colors of CSS 2.1, and `transparent`.
+fn named_color(name: &str) -> Option<Color> {
+    let (r, g, b) = match name {
+        "transparent" => return Some(Color::new(0, 0, 0).with_alpha(0)),
+        "black" => (0, 0, 0),
+        "silver" => (192, 192, 192),
+        "gray" | "grey" => (128, 128, 128),
+        "white" => (255, 255, 255),
+        "maroon" => (128, 0, 0),
+        "red" => (255, 0, 0),
+        "purple" => (128, 0, 128),
+        "fuchsia" | "magenta" => (255, 0, 255),
+        "green" => (0, 128, 0),
+        "lime" => (0, 255, 0),
+        "olive" => (128, 128, 0),
+        "yellow" => (255, 255, 0),
+        "navy" => (0, 0, 128),
+        "blue" => (0, 0, 255),
+        "teal" => (0, 128, 128),
+        "aqua" | "cyan" => (0, 255, 255),
+        "orange" => (255, 165, 0),
+        _ => return None,
+    };
+    Some(Color::new(r, g, b))
+}
+
+fn text_decoration_line(components: &[Component]) -> Option<TextDecorationLine> {
+    match components {
+        [component] => text_decoration_line_keyword(component),
+        _ => None,
+    }
+}
+
+/// Only one line is supported, so `underline overline` can't be used.
+fn text_decoration_line_keyword(component: &Component) -> Option<TextDecorationLine> {
+    match keyword(component)?.as_str() {
+        "none" => Some(TextDecorationLine::None),
+        "underline" => Some(TextDecorationLine::Underline),
+        "overline" => Some(TextDecorationLine::Overline),
+        "line-through" => Some(TextDecorationLine::LineThrough),
+        _ => None,
+    }
+}
+
+fn text_decoration_style(component: &Component) -> Option<TextDecorationStyle> {
+    match keyword(component)?.as_str() {
+        "solid" => Some(TextDecorationStyle::Solid),
+        "double" => Some(TextDecorationStyle::Double),
+        "dotted" => Some(TextDecorationStyle::Dotted),
+        "dashed" => Some(TextDecorationStyle::Dashed),
+        "wavy" => Some(TextDecorationStyle::Wavy),
+        _ => None,
+    }
+}
+
+/// `text-decoration: line || style || color`, in any order.
+fn text_decoration(components: &[Component]) -> Option<Vec<PropertyDeclaration>> {
+    let mut line = None;
+    let mut decoration_style = None;
+    let mut decoration_color = None;
+    for component in components {
+        if let Some(value) = text_decoration_line_keyword(component).filter(|_| line.is_none()) {
+            line = Some(value);
+        } else if let Some(value) =
+            text_decoration_style(component).filter(|_| decoration_style.is_none())
+        {
+            decoration_style = Some(value);
+        } else if let Some(value) = color(component).filter(|_| decoration_color.is_none()) {
+            decoration_color = Some(value);
+        } else {
+            return None;
+        }
+    }
+    Some(vec![
+        PropertyDeclaration::TextDecorationLine(line.unwrap_or_default()),
+        PropertyDeclaration::TextDecorationStyle(decoration_style.unwrap_or_default()),
+        PropertyDeclaration::TextDecorationColor(
+            decoration_color.unwrap_or(CSSColor::CurrentColor),
+        ),
+    ])
+}
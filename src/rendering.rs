@@ -2,48 +2,121 @@ use std::collections::HashMap;
 
 use macroquad::{
     math::Vec2,
-    shapes::{draw_circle, draw_line, draw_rectangle_lines},
     text::{measure_text, Font, TextDimensions},
 };
-use pest::Position;
 
 use crate::{
-    dom::{DOMAction, DOMElement, DOM},
-    styling::{Display, FontFamily, FontWeight, TextDecorationLine},
+    dom::{DOMAction, DOMElement, NodeId, DOM},
+    styling::{Color, Display, FontFamily, FontWeight, TextDecorationLine},
 };
 
-pub fn render_dom(
+pub(crate) type ElementBox = (BoundingBox, Vec<DOMAction>, NodeId);
+pub(crate) type DrawText<'a> =
+    &'a dyn Fn(&str, f32, f32, u16, macroquad::color::Color, &Font) -> TextDimensions;
+pub(crate) type DrawLine<'a> = &'a dyn Fn(Vec2, Vec2, macroquad::color::Color);
+
+/// Where everything on a page goes. It's kept between frames until the DOM
+/// says it needs to be laid out again, see `DOM::take_layout_dirty`.
+pub(crate) struct Layout {
+    /// Innermost first
+    pub element_boxes: Vec<ElementBox>,
+    commands: Vec<DrawCommand>,
+    /// The window width the page was laid out for
+    pub width: f32,
+}
+
+enum DrawCommand {
+    Text {
+        text: String,
+        position: Point,
+        font_size: u16,
+        color: Color,
+        font: (FontFamily, FontWeight),
+    },
+    Line {
+        start: Vec2,
+        end: Vec2,
+        color: Color,
+    },
+}
+
+pub fn layout_dom(
     dom: &DOM,
-    draw_text: &dyn Fn(&str, f32, f32, u16, macroquad::color::Color, &Font) -> TextDimensions,
-    draw_line: &dyn Fn(Vec2, Vec2, macroquad::color::Color),
     fonts: &HashMap<(FontFamily, FontWeight), Font>,
-) -> Vec<(BoundingBox, Vec<DOMAction>, String)> {
-    macroquad::window::clear_background(macroquad::color::WHITE);
-
+    width: f32,
+) -> Layout {
     let bbox = BoundingBox {
         x: 0.0,
         y: 0.0,
-        width: macroquad::window::screen_width(),
+        width,
         height: macroquad::window::screen_height(),
     };
 
     let mut position = Point { x: 0.0, y: 0.0 };
 
     let mut element_boxes = vec![];
+    let mut commands = vec![];
 
-    for element in dom.elements.iter() {
-        position = render_dom_element(
-            element,
+    for &root in dom.roots() {
+        position = layout_dom_element(
+            dom,
+            root,
             bbox,
             position,
-            draw_text,
-            draw_line,
+            &mut commands,
             fonts,
             &mut element_boxes,
         );
     }
 
-    element_boxes
+    Layout {
+        element_boxes,
+        commands,
+        width,
+    }
+}
+
+/// Draws a page laid out by `layout_dom`.
+pub fn paint(
+    layout: &Layout,
+    draw_text: DrawText,
+    draw_line: DrawLine,
+    fonts: &HashMap<(FontFamily, FontWeight), Font>,
+) {
+    macroquad::window::clear_background(macroquad::color::WHITE);
+    for command in &layout.commands {
+        match command {
+            DrawCommand::Text {
+                text,
+                position,
+                font_size,
+                color,
+                font,
+            } => {
+                draw_text(
+                    text,
+                    position.x,
+                    position.y,
+                    *font_size,
+                    (*color).into(),
+                    font_for(fonts, *font).unwrap(),
+                );
+            }
+            DrawCommand::Line { start, end, color } => draw_line(*start, *end, (*color).into()),
+        }
+    }
+}
+
+/// The font loaded for `font`, or else the Times New Roman of the same weight
+/// that `browse` always loads.
+fn font_for<F>(
+    fonts: &HashMap<(FontFamily, FontWeight), F>,
+    font: (FontFamily, FontWeight),
+) -> Option<&F> {
+    let (_, weight) = font;
+    fonts
+        .get(&font)
+        .or_else(|| fonts.get(&(FontFamily::TimesNewRoman, weight)))
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -81,57 +154,57 @@ impl From<(f32, f32)> for Point {
     }
 }
 
-pub(crate) fn render_dom_element(
-    element: &DOMElement,
+fn layout_dom_element(
+    dom: &DOM,
+    id: NodeId,
     bbox: BoundingBox,
     position: Point,
-    draw_text: &dyn Fn(&str, f32, f32, u16, macroquad::color::Color, &Font) -> TextDimensions,
-    draw_line: &dyn Fn(Vec2, Vec2, macroquad::color::Color),
+    commands: &mut Vec<DrawCommand>,
     fonts: &HashMap<(FontFamily, FontWeight), Font>,
-    element_boxes: &mut Vec<(BoundingBox, Vec<DOMAction>, String)>,
+    element_boxes: &mut Vec<ElementBox>,
 ) -> Point {
     let mut cursor = position;
     let mut bbox = bbox;
-    match element {
-        DOMElement::View {
-            style,
-            children,
-            actions,
-            id,
-            tag,
-            ..
-        } => match style.display {
+    match &dom.node(id).element {
+        DOMElement::View { style, actions, .. } => match style.display {
             Display::Block => {
-                let line_height = style.font.size.to_pixels(16.0);
-                let margin_top = style.margin.top.to_pixels(line_height);
-                let margin_left = style.margin.left.to_pixels(line_height);
+                let line_height = style.font.size.computed();
+                let margin_top = style.margin.top.computed();
+                let margin_left = style.margin.left.computed();
 
                 cursor.y += margin_top;
                 bbox.x += margin_left;
                 cursor.x = bbox.x;
 
                 let mut last_child = None;
-                for child in children {
+                for child in dom.children(id) {
+                    // Whitespace between blocks collapses away, and between
+                    // inlines the words are already spaced out
+                    if let DOMElement::Text { text, .. } = &dom.node(child).element {
+                        if text.chars().all(|c| c.is_ascii_whitespace()) {
+                            continue;
+                        }
+                    }
+                    let child_display = dom.node(child).element.style().display;
                     if let Some(last_child) = last_child {
-                        if last_child == Display::Inline && child.style().display == Display::Block
-                        {
+                        if last_child == Display::Inline && child_display == Display::Block {
                             cursor.y += line_height;
                             cursor.x = bbox.x;
                         }
                     }
-                    cursor = render_dom_element(
+                    cursor = layout_dom_element(
+                        dom,
                         child,
                         bbox,
                         cursor,
-                        draw_text,
-                        draw_line,
+                        commands,
                         fonts,
                         element_boxes,
                     );
-                    last_child = Some(child.style().display);
+                    last_child = Some(child_display);
                 }
-                let margin_bottom = style.margin.bottom.to_pixels(line_height);
-                let margin_right = style.margin.right.to_pixels(line_height);
+                let margin_bottom = style.margin.bottom.computed();
+                let margin_right = style.margin.right.computed();
 
                 element_boxes.push((
                     BoundingBox {
@@ -141,20 +214,20 @@ pub(crate) fn render_dom_element(
                         height: cursor.y - position.y + margin_bottom,
                     },
                     actions.clone(),
-                    id.clone(),
+                    id,
                 ));
 
                 Point::new(position.x, cursor.y + line_height + margin_bottom)
             }
             Display::Inline => {
                 let mut cursor = position;
-                for child in children {
-                    cursor = render_dom_element(
+                for child in dom.children(id) {
+                    cursor = layout_dom_element(
+                        dom,
                         child,
                         bbox,
                         cursor,
-                        draw_text,
-                        draw_line,
+                        commands,
                         fonts,
                         element_boxes,
                     );
@@ -168,7 +241,7 @@ pub(crate) fn render_dom_element(
                         height: cursor.y - position.y,
                     },
                     actions.clone(),
-                    id.clone(),
+                    id,
                 ));
 
                 Point::new(cursor.x, cursor.y)
@@ -178,16 +251,16 @@ pub(crate) fn render_dom_element(
             text,
             style,
             actions,
-            id,
         } => {
             // Tokenization
             let mut local_element_boxes = vec![];
-            let tokens = text.split_whitespace();
-            let line_height = style.font.size.to_pixels(16.0);
+            // Break on ASCII whitespace only so non-breaking spaces stay inside a token
+            let tokens = text.split_ascii_whitespace();
+            let line_height = style.font.size.computed();
             let space_width = measure_text(
                 " ",
-                fonts.get(&(style.font.family, style.font.weight)),
-                style.font.size.to_pixels(16.0).round() as u16,
+                font_for(fonts, (style.font.family, style.font.weight)),
+                style.font.size.computed().round() as u16,
                 1.0,
             );
             let mut line_beginning = cursor.x;
@@ -195,7 +268,7 @@ pub(crate) fn render_dom_element(
             for token in tokens {
                 let dimensions = measure_text(
                     token,
-                    fonts.get(&(style.font.family, style.font.weight)),
+                    font_for(fonts, (style.font.family, style.font.weight)),
                     line_height.round() as u16,
                     1.0,
                 );
@@ -209,7 +282,7 @@ pub(crate) fn render_dom_element(
                             height: line_height,
                         },
                         actions.clone(),
-                        id.clone(),
+                        id,
                     ));
 
                     // draw_rectangle_lines(
@@ -227,21 +300,20 @@ pub(crate) fn render_dom_element(
                 }
 
                 if let TextDecorationLine::Underline = style.text_decoration.line {
-                    draw_line(
-                        Vec2::new(cursor.x, cursor.y + line_height),
-                        Vec2::new(cursor.x + dimensions.width, cursor.y + line_height),
-                        style.text_decoration.color.into(),
-                    );
+                    commands.push(DrawCommand::Line {
+                        start: Vec2::new(cursor.x, cursor.y + line_height),
+                        end: Vec2::new(cursor.x + dimensions.width, cursor.y + line_height),
+                        color: style.text_decoration.color,
+                    });
                 }
 
-                draw_text(
-                    &token,
-                    cursor.x,
-                    cursor.y + line_height,
-                    line_height.round() as u16,
-                    style.color.into(),
-                    fonts.get(&(style.font.family, style.font.weight)).unwrap(),
-                );
+                commands.push(DrawCommand::Text {
+                    text: token.to_string(),
+                    position: Point::new(cursor.x, cursor.y + line_height),
+                    font_size: line_height.round() as u16,
+                    color: style.color,
+                    font: (style.font.family, style.font.weight),
+                });
 
                 cursor.x += dimensions.width + space_width.width;
             }
@@ -257,7 +329,7 @@ pub(crate) fn render_dom_element(
                         height: line_height,
                     },
                     actions.clone(),
-                    id.clone(),
+                    id,
                 ));
                 // draw_rectangle_lines(
                 //     line_beginning,
@@ -275,3 +347,32 @@ pub(crate) fn render_dom_element(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn sans_serif_text_falls_back_to_times_new_roman() {
+        let fonts = HashMap::from([
+            ((FontFamily::TimesNewRoman, FontWeight::Normal), "tnr.ttf"),
+            ((FontFamily::TimesNewRoman, FontWeight::Bold), "tnrb.ttf"),
+        ]);
+        let dom = DOM::construct_dom(parser::parse(
+            "<style>body { font-family: Helvetica, sans-serif }</style>\
+             <body><p>Plain <b>bold</b></p></body>",
+        ));
+        let fonts_used: Vec<_> = dom
+            .pre_order()
+            .filter_map(|id| match &dom.node(id).element {
+                DOMElement::Text { style, .. } => {
+                    assert_eq!(style.font.family, FontFamily::Arial);
+                    font_for(&fonts, (style.font.family, style.font.weight)).copied()
+                }
+                DOMElement::View { .. } => None,
+            })
+            .collect();
+        assert_eq!(fonts_used, ["tnr.ttf", "tnrb.ttf"]);
+    }
+}
@@ -0,0 +1,592 @@
+use pest::{iterators::Pair, Parser};
+use pest_derive::Parser;
+
+use crate::dom::{is_link, DOMElement, NodeId, DOM};
+
+#[derive(Parser)]
+#[grammar = "selector.pest"]
+pub struct SelectorParser;
+
+/// A comma separated list of selectors, matching elements that match any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SelectorList(pub Vec<Selector>);
+
+/// Compound selectors joined by combinators, like `ul > li.item a`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Selector {
+    /// In source order, the last one matches the subject
+    pub compounds: Vec<CompoundSelector>,
+    /// `combinators[i]` joins `compounds[i]` and `compounds[i + 1]`
+    pub combinators: Vec<Combinator>,
+}
+
+/// Simple selectors that must all match the same element, like `a.ext[href]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompoundSelector(pub Vec<SimpleSelector>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Combinator {
+    /// `a b`
+    Descendant,
+    /// `a > b`
+    Child,
+    /// `a + b`
+    NextSibling,
+    /// `a ~ b`
+    SubsequentSibling,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SimpleSelector {
+    Universal,
+    /// Lowercased tag name
+    Type(String),
+    Id(String),
+    Class(String),
+    Attribute {
+        /// Lowercased attribute name
+        name: String,
+        value: Option<(AttributeOperator, String)>,
+    },
+    PseudoClass(PseudoClass),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AttributeOperator {
+    /// `[name=value]`
+    Equals,
+    /// `[name~=value]`, one of the whitespace separated words
+    Includes,
+    /// `[name|=value]`, `value` or starting with `value-`
+    DashMatch,
+    /// `[name^=value]`
+    Prefix,
+    /// `[name$=value]`
+    Suffix,
+    /// `[name*=value]`
+    Substring,
+}
+
+/// Whose hover state a selector depends on, so moving the pointer only
+/// restyles what it has to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum HoverDependence {
+    None,
+    /// Only the state of the element being matched, like `a:hover`
+    Subject,
+    /// Also that of other elements, like `li:hover > a`
+    Others,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PseudoClass {
+    FirstChild,
+    LastChild,
+    /// Under the pointer, see `DOM::set_hovered`
+    Hover,
+    /// A link that wasn't visited
+    Link,
+    /// See `DOM::update_visited`
+    Visited,
+    /// `:nth-child(an+b)`
+    NthChild {
+        a: i32,
+        b: i32,
+    },
+    Not(SelectorList),
+}
+
+/// How specific a selector is, which decides between conflicting
+/// declarations: counts of ids, of classes, attributes and pseudo-classes,
+/// and of types, compared in that order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Specificity(pub u32, pub u32, pub u32);
+
+impl std::ops::Add for Specificity {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0, self.1 + other.1, self.2 + other.2)
+    }
+}
+
+/// A selector that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SelectorError {
+    pub selector: String,
+    /// Byte offset of the problem
+    pub position: usize,
+}
+
+impl std::fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid selector '{}' at offset {}",
+            self.selector, self.position
+        )
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl SelectorList {
+    pub(crate) fn parse(input: &str) -> Result<Self, SelectorError> {
+        let error = |position| SelectorError {
+            selector: input.to_string(),
+            position,
+        };
+        let list = SelectorParser::parse(Rule::selectorList, input)
+            .map_err(|e| {
+                error(match e.location {
+                    pest::error::InputLocation::Pos(position) => position,
+                    pest::error::InputLocation::Span((start, _)) => start,
+                })
+            })?
+            .next()
+            .unwrap();
+        parse_selectors(list).map_err(error)
+    }
+
+    pub(crate) fn matches(&self, dom: &DOM, id: NodeId) -> bool {
+        self.0.iter().any(|selector| selector.matches(dom, id))
+    }
+
+    /// The specificity of the most specific selector in the list matching
+    /// a node, or `None` if none does.
+    pub(crate) fn matching_specificity(&self, dom: &DOM, id: NodeId) -> Option<Specificity> {
+        self.0
+            .iter()
+            .filter(|selector| selector.matches(dom, id))
+            .map(Selector::specificity)
+            .max()
+    }
+}
+
+impl Selector {
+    pub(crate) fn matches(&self, dom: &DOM, id: NodeId) -> bool {
+        self.matches_compound(dom, id, self.compounds.len() - 1)
+    }
+
+    /// Whose hover state decides whether the selector matches.
+    pub(crate) fn hover_dependence(&self) -> HoverDependence {
+        let (subject, rest) = self.compounds.split_last().unwrap();
+        let rest = match rest.iter().map(CompoundSelector::hover_dependence).max() {
+            Some(HoverDependence::None) | None => HoverDependence::None,
+            Some(_) => HoverDependence::Others,
+        };
+        subject.hover_dependence().max(rest)
+    }
+
+    pub(crate) fn specificity(&self) -> Specificity {
+        self.compounds
+            .iter()
+            .flat_map(|compound| &compound.0)
+            .map(SimpleSelector::specificity)
+            .fold(Specificity::default(), std::ops::Add::add)
+    }
+
+    /// Whether `id` matches `compounds[index]` and the part of the selector
+    /// left of it. Combinators that allow several candidates backtrack.
+    fn matches_compound(&self, dom: &DOM, id: NodeId, index: usize) -> bool {
+        if !self.compounds[index].matches(dom, id) {
+            return false;
+        }
+        if index == 0 {
+            return true;
+        }
+        let index = index - 1;
+        match self.combinators[index] {
+            Combinator::Descendant => dom
+                .ancestors(id)
+                .any(|ancestor| self.matches_compound(dom, ancestor, index)),
+            Combinator::Child => dom
+                .parent(id)
+                .is_some_and(|parent| self.matches_compound(dom, parent, index)),
+            Combinator::NextSibling => previous_element_siblings(dom, id)
+                .next()
+                .is_some_and(|sibling| self.matches_compound(dom, sibling, index)),
+            Combinator::SubsequentSibling => previous_element_siblings(dom, id)
+                .any(|sibling| self.matches_compound(dom, sibling, index)),
+        }
+    }
+}
+
+impl CompoundSelector {
+    fn matches(&self, dom: &DOM, id: NodeId) -> bool {
+        let element = &dom.node(id).element;
+        // Selectors only ever match elements
+        if let DOMElement::Text { .. } = element {
+            return false;
+        }
+        self.0.iter().all(|simple| match simple {
+            SimpleSelector::Universal => true,
+            SimpleSelector::Type(name) => element.tag() == name,
+            SimpleSelector::Id(id) => element.get_attribute("id") == Some(id.as_str()),
+            SimpleSelector::Class(class) => element.class_list().contains(&class.as_str()),
+            SimpleSelector::Attribute { name, value } => {
+                match (element.get_attribute(name), value) {
+                    (None, _) => false,
+                    (Some(_), None) => true,
+                    (Some(actual), Some((operator, expected))) => {
+                        operator.matches(actual, expected)
+                    }
+                }
+            }
+            SimpleSelector::PseudoClass(pseudo_class) => pseudo_class.matches(dom, id),
+        })
+    }
+}
+
+impl CompoundSelector {
+    fn hover_dependence(&self) -> HoverDependence {
+        self.0
+            .iter()
+            .map(|simple| match simple {
+                SimpleSelector::PseudoClass(PseudoClass::Hover) => HoverDependence::Subject,
+                SimpleSelector::PseudoClass(PseudoClass::Not(selectors)) => selectors
+                    .0
+                    .iter()
+                    .map(Selector::hover_dependence)
+                    .max()
+                    .unwrap_or(HoverDependence::None),
+                _ => HoverDependence::None,
+            })
+            .max()
+            .unwrap_or(HoverDependence::None)
+    }
+}
+
+impl SimpleSelector {
+    fn specificity(&self) -> Specificity {
+        match self {
+            Self::Universal => Specificity(0, 0, 0),
+            Self::Type(_) => Specificity(0, 0, 1),
+            Self::Id(_) => Specificity(1, 0, 0),
+            Self::Class(_) | Self::Attribute { .. } => Specificity(0, 1, 0),
+            // `:not()` counts as its most specific argument
+            Self::PseudoClass(PseudoClass::Not(selectors)) => selectors
+                .0
+                .iter()
+                .map(Selector::specificity)
+                .max()
+                .unwrap_or_default(),
+            Self::PseudoClass(_) => Specificity(0, 1, 0),
+        }
+    }
+}
+
+impl AttributeOperator {
+    fn matches(self, actual: &str, expected: &str) -> bool {
+        match self {
+            Self::Equals => actual == expected,
+            Self::Includes => actual.split_ascii_whitespace().any(|word| word == expected),
+            Self::DashMatch => {
+                actual == expected
+                    || actual
+                        .strip_prefix(expected)
+                        .is_some_and(|rest| rest.starts_with('-'))
+            }
+            // Empty values never match for the substring operators
+            Self::Prefix => !expected.is_empty() && actual.starts_with(expected),
+            Self::Suffix => !expected.is_empty() && actual.ends_with(expected),
+            Self::Substring => !expected.is_empty() && actual.contains(expected),
+        }
+    }
+}
+
+impl PseudoClass {
+    fn matches(&self, dom: &DOM, id: NodeId) -> bool {
+        match self {
+            Self::FirstChild => previous_element_siblings(dom, id).next().is_none(),
+            Self::LastChild => next_element_siblings(dom, id).next().is_none(),
+            Self::Hover => dom.node(id).state.hovered,
+            Self::Link => match &dom.node(id).element {
+                DOMElement::View {
+                    tag, attributes, ..
+                } => is_link(tag, attributes) && !dom.node(id).state.visited,
+                DOMElement::Text { .. } => false,
+            },
+            Self::Visited => dom.node(id).state.visited,
+            Self::NthChild { a, b } => {
+                // Wide enough that no `i32` an+b from a style sheet overflows
+                let position = previous_element_siblings(dom, id).count() as i64 + 1;
+                let (a, b) = (i64::from(*a), i64::from(*b));
+                // Is there an n >= 0 with a * n + b == position?
+                match a {
+                    0 => position == b,
+                    a => (position - b) % a == 0 && (position - b) / a >= 0,
+                }
+            }
+            Self::Not(selectors) => !selectors.matches(dom, id),
+        }
+    }
+}
+
+fn previous_element_siblings(dom: &DOM, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+    std::iter::successors(dom.node(id).previous_sibling, |&sibling| {
+        dom.node(sibling).previous_sibling
+    })
+    .filter(|&sibling| matches!(dom.node(sibling).element, DOMElement::View { .. }))
+}
+
+fn next_element_siblings(dom: &DOM, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+    std::iter::successors(dom.node(id).next_sibling, |&sibling| {
+        dom.node(sibling).next_sibling
+    })
+    .filter(|&sibling| matches!(dom.node(sibling).element, DOMElement::View { .. }))
+}
+
+/// Parses the `complexSelector`s inside `pair`. Errors are the byte offset of
+/// a selector the grammar accepts but we don't support.
+fn parse_selectors(pair: Pair<Rule>) -> Result<SelectorList, usize> {
+    pair.into_inner()
+        .filter(|pair| pair.as_rule() == Rule::complexSelector)
+        .map(parse_selector)
+        .collect::<Result<_, _>>()
+        .map(SelectorList)
+}
+
+fn parse_selector(pair: Pair<Rule>) -> Result<Selector, usize> {
+    let mut compounds = vec![];
+    let mut combinators = vec![];
+    for pair in pair.into_inner() {
+        match pair.as_rule() {
+            Rule::compoundSelector => compounds.push(parse_compound(pair)?),
+            Rule::combinator => {
+                let combinator = match pair.into_inner().next().unwrap().as_rule() {
+                    Rule::child => Combinator::Child,
+                    Rule::nextSibling => Combinator::NextSibling,
+                    Rule::subsequentSibling => Combinator::SubsequentSibling,
+                    _ => Combinator::Descendant,
+                };
+                combinators.push(combinator);
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(Selector {
+        compounds,
+        combinators,
+    })
+}
+
+fn parse_compound(pair: Pair<Rule>) -> Result<CompoundSelector, usize> {
+    let mut simple_selectors = vec![];
+    for pair in pair.into_inner() {
+        let simple = match pair.as_rule() {
+            Rule::universal => SimpleSelector::Universal,
+            Rule::typeSelector => SimpleSelector::Type(pair.as_str().to_ascii_lowercase()),
+            Rule::idSelector => SimpleSelector::Id(inner_str(pair)),
+            Rule::classSelector => SimpleSelector::Class(inner_str(pair)),
+            Rule::attributeSelector => {
+                let mut inner = pair.into_inner();
+                let name = inner.next().unwrap().as_str().to_ascii_lowercase();
+                let value = inner.next().map(|operator| {
+                    let operator = match operator.as_str() {
+                        "=" => AttributeOperator::Equals,
+                        "~=" => AttributeOperator::Includes,
+                        "|=" => AttributeOperator::DashMatch,
+                        "^=" => AttributeOperator::Prefix,
+                        "$=" => AttributeOperator::Suffix,
+                        "*=" => AttributeOperator::Substring,
+                        _ => unreachable!(),
+                    };
+                    (operator, inner_str(inner.next().unwrap()))
+                });
+                SimpleSelector::Attribute { name, value }
+            }
+            Rule::pseudoClass => SimpleSelector::PseudoClass(parse_pseudo_class(pair)?),
+            _ => unreachable!(),
+        };
+        simple_selectors.push(simple);
+    }
+    Ok(CompoundSelector(simple_selectors))
+}
+
+fn parse_pseudo_class(pair: Pair<Rule>) -> Result<PseudoClass, usize> {
+    let position = pair.as_span().start();
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::nthChild => {
+            let nth = pair.into_inner().next().unwrap().as_str();
+            let (a, b) = parse_nth(nth).ok_or(position)?;
+            Ok(PseudoClass::NthChild { a, b })
+        }
+        Rule::not => Ok(PseudoClass::Not(parse_selectors(pair)?)),
+        _ => match pair.as_str().to_ascii_lowercase().as_str() {
+            "first-child" => Ok(PseudoClass::FirstChild),
+            "last-child" => Ok(PseudoClass::LastChild),
+            "hover" => Ok(PseudoClass::Hover),
+            "link" => Ok(PseudoClass::Link),
+            "visited" => Ok(PseudoClass::Visited),
+            _ => Err(position),
+        },
+    }
+}
+
+/// Parses the `an+b` notation, which the grammar has already checked.
+fn parse_nth(nth: &str) -> Option<(i32, i32)> {
+    let nth: String = nth
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match nth.as_str() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+    let Some((a, b)) = nth.split_once('n') else {
+        return Some((0, nth.parse().ok()?));
+    };
+    let a = match a {
+        "" | "+" => 1,
+        "-" => -1,
+        a => a.parse().ok()?,
+    };
+    let b = match b {
+        "" => 0,
+        b => b.strip_prefix('+').unwrap_or(b).parse().ok()?,
+    };
+    Some((a, b))
+}
+
+/// The text of the first inner pair, like the value in `"value"`.
+fn inner_str(pair: Pair<Rule>) -> String {
+    pair.into_inner().next().unwrap().as_str().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    const PAGE: &str = r#"<div id="root" class="box main">
+        <p id="first" lang="en-US" title="a b c">One</p>
+        <p id="second" class="note">Two <a id="link" href="/x">link</a></p>
+        <ul id="list"><li id="li1">1<li id="li2">2<li id="li3">3<li id="li4">4<li id="li5">5</ul>
+        <span id="last" data-kind="tail-end">End</span>
+    </div>"#;
+
+    fn select(selectors: &str) -> Vec<String> {
+        let dom = DOM::construct_dom(parser::parse(PAGE));
+        dom.query_selector_all(selectors)
+            .unwrap()
+            .into_iter()
+            .map(|id| {
+                dom.node(id)
+                    .element
+                    .get_attribute("id")
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn specificity(selector: &str) -> Specificity {
+        SelectorList::parse(selector).unwrap().0[0].specificity()
+    }
+
+    #[test]
+    fn simple_selectors() {
+        assert_eq!(select("p"), ["first", "second"]);
+        assert_eq!(select("P"), ["first", "second"]);
+        assert_eq!(select("#link"), ["link"]);
+        assert_eq!(select(".main"), ["root"]);
+        assert_eq!(select("div.box.main"), ["root"]);
+        assert_eq!(select("*").len(), 11);
+        assert_eq!(select("p, #last"), ["first", "second", "last"]);
+    }
+
+    #[test]
+    fn attribute_selectors() {
+        assert_eq!(select("[href]"), ["link"]);
+        assert_eq!(select("[href=\"/x\"]"), ["link"]);
+        assert_eq!(select("[title~=b]"), ["first"]);
+        assert_eq!(select("[lang|=en]"), ["first"]);
+        assert_eq!(select("[data-kind^=tail]"), ["last"]);
+        assert_eq!(select("[data-kind$='end']"), ["last"]);
+        assert_eq!(select("[data-kind*=l-e]"), ["last"]);
+        assert!(select("[data-kind^='']").is_empty());
+    }
+
+    #[test]
+    fn combinators() {
+        assert_eq!(select("div a"), ["link"]);
+        assert!(select("div > a").is_empty());
+        assert_eq!(select("p > a"), ["link"]);
+        assert_eq!(select("#first + p"), ["second"]);
+        assert_eq!(select("#first ~ *"), ["second", "list", "last"]);
+        // Backtracks to a `p` further up than the nearest ancestor
+        assert_eq!(select("div p a"), ["link"]);
+    }
+
+    #[test]
+    fn pseudo_classes() {
+        assert_eq!(select("li:first-child"), ["li1"]);
+        assert_eq!(select("li:last-child"), ["li5"]);
+        assert_eq!(select("li:nth-child(odd)"), ["li1", "li3", "li5"]);
+        assert_eq!(select("li:nth-child(2n)"), ["li2", "li4"]);
+        assert_eq!(select("li:nth-child(-n + 2)"), ["li1", "li2"]);
+        assert_eq!(select("li:nth-child(3)"), ["li3"]);
+        assert_eq!(
+            select("li:not(:first-child, :last-child)"),
+            ["li2", "li3", "li4"]
+        );
+        assert_eq!(select(":link"), ["link"]);
+        assert!(select(":visited").is_empty());
+    }
+
+    #[test]
+    fn nth_child_never_overflows() {
+        assert_eq!(select("li:nth-child(-n+2147483647)").len(), 5);
+        assert_eq!(select("li:nth-child(n-2147483648)").len(), 5);
+        assert!(select("li:nth-child(-2147483648n+2147483647)").is_empty());
+        assert!(select("li:nth-child(2147483647n-2147483648)").is_empty());
+    }
+
+    #[test]
+    fn invalid_selectors() {
+        let position = |selector| SelectorList::parse(selector).unwrap_err().position;
+        assert_eq!(position("p >"), 3);
+        assert_eq!(position("p, "), 3);
+        assert_eq!(position("a:unknown"), 1);
+        assert_eq!(position("li:nth-child(n+99999999999)"), 2);
+        assert!(SelectorList::parse("").is_err());
+    }
+
+    #[test]
+    fn specificities() {
+        assert_eq!(specificity("*"), Specificity(0, 0, 0));
+        assert_eq!(specificity("ul li"), Specificity(0, 0, 2));
+        assert_eq!(specificity("li.note:first-child"), Specificity(0, 2, 1));
+        assert_eq!(specificity("#list > li[id]"), Specificity(1, 1, 1));
+        assert_eq!(specificity(":not(#a, .b) p"), Specificity(1, 0, 1));
+        assert!(specificity("#a") > specificity(".a.b.c.d p"));
+    }
+
+    #[test]
+    fn hover_dependence() {
+        let dependence = |selector| SelectorList::parse(selector).unwrap().0[0].hover_dependence();
+        assert_eq!(dependence("a:link"), HoverDependence::None);
+        assert_eq!(dependence(":link:hover"), HoverDependence::Subject);
+        assert_eq!(dependence("div a:not(:hover)"), HoverDependence::Subject);
+        assert_eq!(dependence("li:hover > a"), HoverDependence::Others);
+        assert_eq!(dependence("p:hover + p:hover"), HoverDependence::Others);
+        assert_eq!(dependence(":not(li:hover a)"), HoverDependence::Others);
+    }
+
+    #[test]
+    fn matching_specificity_is_of_the_most_specific_match() {
+        let dom = DOM::construct_dom(parser::parse(PAGE));
+        let link = dom.query_selector("#link").unwrap().unwrap();
+        let list = SelectorList::parse("a, p a, #nothing").unwrap();
+        assert_eq!(
+            list.matching_specificity(&dom, link),
+            Some(Specificity(0, 0, 2))
+        );
+        let list = SelectorList::parse("p").unwrap();
+        assert_eq!(list.matching_specificity(&dom, link), None);
+    }
+}
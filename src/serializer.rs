@@ -0,0 +1,192 @@
+use crate::{
+    html::{is_void_element, HTMLElement},
+    parser::{text_mode, TextMode},
+};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SerializeOptions {
+    /// Put block content on its own lines, indented by two spaces per level.
+    /// Elements with text children are kept on one line so no whitespace is
+    /// added where it would be rendered.
+    pub pretty: bool,
+}
+
+/// Serializes a tree back to HTML following the spec's fragment serialization
+/// algorithm: tag names are lowercased, text and attribute values are escaped,
+/// void elements have no end tag and raw text content is written verbatim.
+pub(crate) fn to_html(elements: &[HTMLElement], options: SerializeOptions) -> String {
+    let mut output = String::new();
+    for element in elements {
+        if options.pretty && element.is_whitespace() {
+            continue;
+        }
+        write_node(&mut output, element, None, options, 0);
+        if options.pretty {
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn write_node(
+    output: &mut String,
+    node: &HTMLElement,
+    parent: Option<&str>,
+    options: SerializeOptions,
+    depth: usize,
+) {
+    match node {
+        HTMLElement::Text { text, .. } => match parent.and_then(text_mode) {
+            Some(TextMode::RawText | TextMode::PlainText) => output.push_str(text),
+            _ => output.push_str(&escape_text(text)),
+        },
+        HTMLElement::Comment { text, .. } => {
+            output.push_str("<!--");
+            output.push_str(text);
+            output.push_str("-->");
+        }
+        HTMLElement::Doctype { text, .. } => {
+            output.push_str("<!DOCTYPE");
+            if !text.is_empty() {
+                output.push(' ');
+                output.push_str(text);
+            }
+            output.push('>');
+        }
+        HTMLElement::Element {
+            name,
+            attributes,
+            children,
+            ..
+        } => {
+            output.push('<');
+            output.push_str(name);
+            for (attribute, value) in attributes {
+                output.push(' ');
+                output.push_str(attribute);
+                output.push_str("=\"");
+                output.push_str(&escape_attribute(value));
+                output.push('"');
+            }
+            output.push('>');
+
+            if is_void_element(name) {
+                return;
+            }
+
+            let block = options.pretty
+                && !children.is_empty()
+                && text_mode(name).is_none()
+                && name != "pre"
+                && children.iter().all(|child| {
+                    !matches!(child, HTMLElement::Text { .. }) || child.is_whitespace()
+                });
+
+            for child in children {
+                // The indentation replaces whitespace between blocks
+                if block && child.is_whitespace() {
+                    continue;
+                }
+                if block {
+                    output.push('\n');
+                    output.push_str(&"  ".repeat(depth + 1));
+                }
+                let options = if block {
+                    options
+                } else {
+                    SerializeOptions { pretty: false }
+                };
+                write_node(output, child, Some(name), options, depth + 1);
+            }
+            if block {
+                output.push('\n');
+                output.push_str(&"  ".repeat(depth));
+            }
+
+            output.push_str("</");
+            output.push_str(name);
+            output.push('>');
+        }
+    }
+}
+
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn normalize(html: &str) -> String {
+        to_html(&parser::parse(html), SerializeOptions::default())
+    }
+
+    #[test]
+    fn round_trip_keeps_whitespace_between_inline_elements() {
+        let html = "<p><b>a</b> <i>b</i>\n<em>c</em></p><pre>x\n\n<b>y</b>\n  \n</pre>";
+        let normalized = normalize(html);
+        assert_eq!(normalized, html);
+        assert_eq!(normalize(&normalized), normalized);
+    }
+
+    #[test]
+    fn round_trip_escapes_text_and_attributes() {
+        let html = "<a title='\"1 &amp; 2\"'>a &lt; b&nbsp;c</a><br><script>if (a < b) {}</script>";
+        let normalized = normalize(html);
+        assert_eq!(
+            normalized,
+            "<a title=\"&quot;1 &amp; 2&quot;\">a &lt; b&nbsp;c</a><br><script>if (a < b) {}</script>"
+        );
+        assert_eq!(normalize(&normalized), normalized);
+    }
+
+    #[test]
+    fn round_trip_keeps_the_doctype_and_comments() {
+        let html = "<!DOCTYPE html>\n<!-- top --><html><body><p>a<!--b-->c</p></body></html>";
+        let normalized = normalize(html);
+        assert_eq!(
+            normalized,
+            "<!DOCTYPE html><!-- top --><html><body><p>a<!--b-->c</p></body></html>"
+        );
+        assert_eq!(normalize(&normalized), normalized);
+        // Legacy doctypes keep their public identifier, bogus comments become comments
+        assert_eq!(
+            normalize("<!doctype HTML PUBLIC \"-//W3C//DTD HTML 3.2//EN\"><?xml x?><p><!x>"),
+            "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 3.2//EN\"><!--?xml x?--><p><!--x--></p>"
+        );
+    }
+
+    #[test]
+    fn pretty_printing_puts_comments_on_their_own_lines() {
+        let html = "<!DOCTYPE html><ul><!-- items --><li>a</li></ul>";
+        assert_eq!(
+            to_html(&parser::parse(html), SerializeOptions { pretty: true }),
+            "<!DOCTYPE html>\n<ul>\n  <!-- items -->\n  <li>a</li>\n</ul>\n"
+        );
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone)]
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct Style {
     pub display: Display,
     pub margin: Margin,
@@ -7,24 +9,6 @@ pub(crate) struct Style {
     pub text_decoration: TextDecoration,
 }
 
-impl Style {
-    pub(crate) fn new(
-        display: Display,
-        margin: Margin,
-        font: Font,
-        color: Color,
-        text_decoration: TextDecoration,
-    ) -> Self {
-        Self {
-            display,
-            margin,
-            font,
-            color,
-            text_decoration,
-        }
-    }
-}
-
 impl Default for Style {
     fn default() -> Self {
         Self {
@@ -37,19 +21,15 @@ impl Default for Style {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum Display {
     Block,
+    #[default]
     Inline,
 }
 
-impl Default for Display {
-    fn default() -> Self {
-        Self::Inline
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub(crate) struct Margin {
     pub top: Unit,
     pub right: Unit,
@@ -57,17 +37,6 @@ pub(crate) struct Margin {
     pub left: Unit,
 }
 
-impl Margin {
-    pub(crate) fn new(top: Unit, right: Unit, bottom: Unit, left: Unit) -> Self {
-        Self {
-            top,
-            right,
-            bottom,
-            left,
-        }
-    }
-}
-
 impl Default for Margin {
     fn default() -> Self {
         Self {
@@ -86,17 +55,44 @@ pub(crate) enum Unit {
     Rem(f32),
 }
 
+/// Written like in CSS, like `1.5em`.
+impl std::fmt::Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Px(px) => write!(f, "{}px", px),
+            Self::Em(m) => write!(f, "{}em", m),
+            Self::Rem(m) => write!(f, "{}rem", m),
+        }
+    }
+}
+
+impl Serialize for Unit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl Unit {
-    pub(crate) fn to_pixels(&self, scale: f32) -> f32 {
+    /// In pixels, `em` being relative to `font_size` and `rem` to the font
+    /// size of the root element.
+    pub(crate) fn to_pixels(self, font_size: f32, root_font_size: f32) -> f32 {
         match self {
-            Self::Px(px) => *px,
-            Self::Em(m) => scale * m,
-            Self::Rem(m) => 16.0 * m,
+            Self::Px(px) => px,
+            Self::Em(m) => font_size * m,
+            Self::Rem(m) => root_font_size * m,
+        }
+    }
+
+    /// A computed length, which the cascade always leaves in pixels.
+    pub(crate) fn computed(self) -> f32 {
+        match self {
+            Self::Px(px) => px,
+            Self::Em(_) | Self::Rem(_) => unreachable!("{} wasn't computed", self),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub(crate) struct Font {
     pub size: Unit,
     pub family: FontFamily,
@@ -104,17 +100,6 @@ pub(crate) struct Font {
     pub style: FontStyle,
 }
 
-impl Font {
-    pub(crate) fn new(size: Unit, family: FontFamily, weight: FontWeight, style: FontStyle) -> Self {
-        Self {
-            size,
-            family,
-            weight,
-            style,
-        }
-    }
-}
-
 impl Default for Font {
     fn default() -> Self {
         Self {
@@ -126,43 +111,30 @@ impl Default for Font {
     }
 }
 
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FontFamily {
+    #[default]
     TimesNewRoman,
     Arial,
 }
 
-impl Default for FontFamily {
-    fn default() -> Self {
-        Self::TimesNewRoman
-    }
-    
-}
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FontWeight {
+    #[default]
     Normal,
     Bold,
 }
 
-impl Default for FontWeight {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
-
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum FontStyle {
+    #[default]
     Normal,
     Italic,
 }
 
-impl Default for FontStyle {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
-
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Color {
     r: u8,
@@ -186,6 +158,28 @@ impl Color {
     pub(crate) fn new(r: u8, g: u8, b: u8) -> Self {
         Self { r, g, b, a: 255 }
     }
+
+    pub(crate) fn with_alpha(self, a: u8) -> Self {
+        Self { a, ..self }
+    }
+}
+
+/// Written like in CSS, like `#551a8b`, with the alpha only if it isn't
+/// opaque.
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)?;
+        if self.a != 255 {
+            write!(f, "{:02x}", self.a)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
 }
 
 impl From<Color> for macroquad::color::Color {
@@ -194,48 +188,30 @@ impl From<Color> for macroquad::color::Color {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub(crate) struct TextDecoration {
     pub color: Color,
     pub line: TextDecorationLine,
     pub style: TextDecorationStyle,
 }
 
-impl Default for TextDecoration {
-    fn default() -> Self {
-        Self {
-            color: Color::default(),
-            line: TextDecorationLine::default(),
-            style: TextDecorationStyle::default(),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum TextDecorationLine {
+    #[default]
     None,
     Underline,
     Overline,
     LineThrough,
 }
 
-impl Default for TextDecorationLine {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum TextDecorationStyle {
+    #[default]
     Solid,
     Double,
     Dotted,
     Dashed,
     Wavy,
 }
-
-impl Default for TextDecorationStyle {
-    fn default() -> Self {
-        Self::Solid
-    }
-}
@@ -0,0 +1,371 @@
+use crate::{
+    dom::{is_link, DOMElement, NodeId, DOM},
+    styling::Display,
+};
+
+/// Walks nodes parent first, in document order, by following the sibling
+/// links instead of keeping a stack.
+pub(crate) struct PreOrder<'a> {
+    dom: &'a DOM,
+    next: Option<NodeId>,
+    /// The node whose subtree is walked, or `None` for the whole document
+    scope: Option<NodeId>,
+}
+
+impl Iterator for PreOrder<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = self.dom.node(current).first_child.or_else(|| {
+            let mut id = current;
+            loop {
+                if Some(id) == self.scope {
+                    return None;
+                }
+                if let Some(sibling) = self.dom.node(id).next_sibling {
+                    return Some(sibling);
+                }
+                id = self.dom.parent(id)?;
+            }
+        });
+        Some(current)
+    }
+}
+
+/// Walks nodes children first, like needed to compute something from the
+/// bottom up.
+pub(crate) struct PostOrder<'a> {
+    dom: &'a DOM,
+    next: Option<NodeId>,
+    /// The node whose subtree is walked, or `None` for the whole document
+    scope: Option<NodeId>,
+}
+
+impl<'a> PostOrder<'a> {
+    fn new(dom: &'a DOM, start: Option<NodeId>, scope: Option<NodeId>) -> Self {
+        Self {
+            dom,
+            next: start.map(|start| first_leaf(dom, start)),
+            scope,
+        }
+    }
+}
+
+impl Iterator for PostOrder<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = if Some(current) == self.scope {
+            None
+        } else if let Some(sibling) = self.dom.node(current).next_sibling {
+            Some(first_leaf(self.dom, sibling))
+        } else {
+            self.dom.parent(current)
+        };
+        Some(current)
+    }
+}
+
+/// The first node of a subtree in post-order.
+fn first_leaf(dom: &DOM, mut id: NodeId) -> NodeId {
+    while let Some(child) = dom.node(id).first_child {
+        id = child;
+    }
+    id
+}
+
+/// Callbacks for `DOM::walk`.
+pub(crate) trait Visitor {
+    /// Called before the children of a node. Returning `false` skips them,
+    /// but `leave` is still called.
+    fn enter(&mut self, dom: &DOM, id: NodeId) -> bool;
+
+    /// Called after the children of a node.
+    fn leave(&mut self, _dom: &DOM, _id: NodeId) {}
+}
+
+impl DOM {
+    /// Every attached node, in document order.
+    pub(crate) fn pre_order(&self) -> PreOrder<'_> {
+        PreOrder {
+            dom: self,
+            next: self.roots().first().copied(),
+            scope: None,
+        }
+    }
+
+    /// Every attached node, children before their parent.
+    #[allow(dead_code, reason = "for tools computing something bottom up")]
+    pub(crate) fn post_order(&self) -> PostOrder<'_> {
+        PostOrder::new(self, self.roots().first().copied(), None)
+    }
+
+    /// The nodes inside `id`, in document order.
+    pub(crate) fn descendants(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        PreOrder {
+            dom: self,
+            next: Some(id),
+            scope: Some(id),
+        }
+        .skip(1)
+    }
+
+    /// The nodes inside `id` and then `id` itself, children before their
+    /// parent.
+    #[allow(dead_code, reason = "`post_order` of one subtree, for the same tools")]
+    pub(crate) fn descendants_post_order(&self, id: NodeId) -> PostOrder<'_> {
+        PostOrder::new(self, Some(id), Some(id))
+    }
+
+    /// The parent of `id`, its parent and so on up to the root.
+    pub(crate) fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.parent(id), |&id| self.parent(id))
+    }
+
+    /// Calls `visitor` around every attached node, in document order.
+    pub(crate) fn walk(&self, visitor: &mut impl Visitor) {
+        for &root in self.roots() {
+            self.walk_from(root, visitor);
+        }
+    }
+
+    /// Calls `visitor` around `id` and the nodes inside it.
+    pub(crate) fn walk_from(&self, id: NodeId, visitor: &mut impl Visitor) {
+        // Nodes entered but not left yet, with whether their children are
+        // still to be visited
+        let mut stack = vec![(id, true)];
+        while let Some((id, enter)) = stack.pop() {
+            if !enter {
+                visitor.leave(self, id);
+                continue;
+            }
+            let visit_children = visitor.enter(self, id);
+            stack.push((id, false));
+            if visit_children {
+                let children: Vec<_> = self.children(id).collect();
+                stack.extend(children.into_iter().rev().map(|child| (child, true)));
+            }
+        }
+    }
+
+    /// The text of every text node inside `id`, as written in the source.
+    #[allow(dead_code, reason = "for tools, `--text` prints `inner_text`")]
+    pub(crate) fn text_content(&self, id: NodeId) -> String {
+        std::iter::once(id)
+            .chain(self.descendants(id))
+            .filter_map(|id| match &self.node(id).element {
+                DOMElement::Text { text, .. } => Some(text.as_str()),
+                DOMElement::View { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The text inside `id` as it would read on screen: whitespace collapsed
+    /// except in preformatted elements, and block elements on lines of their
+    /// own, with paragraphs separated by blank lines.
+    pub(crate) fn inner_text(&self, id: NodeId) -> String {
+        let mut extractor = TextExtractor::default();
+        self.walk_from(id, &mut extractor);
+        extractor.finish()
+    }
+
+    /// The `href` and text of every link, in document order.
+    pub(crate) fn links(&self) -> Vec<(String, String)> {
+        self.pre_order()
+            .filter_map(|id| match &self.node(id).element {
+                DOMElement::View {
+                    tag, attributes, ..
+                } if is_link(tag, attributes) => {
+                    Some((attributes["href"].clone(), self.inner_text(id)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Builds `inner_text` from the runs of inline text between block boundaries.
+#[derive(Default)]
+struct TextExtractor {
+    output: String,
+    /// Line breaks owed before the next text, taking the most any boundary
+    /// asked for
+    line_breaks: usize,
+    /// Collapsed whitespace that is only written if more text follows on
+    /// the same line
+    pending_space: bool,
+    /// How many preformatted elements the walk is inside
+    preformatted: usize,
+}
+
+impl TextExtractor {
+    fn boundary(&mut self, line_breaks: usize) {
+        if line_breaks == 0 {
+            return;
+        }
+        self.line_breaks = self.line_breaks.max(line_breaks);
+        self.pending_space = false;
+    }
+
+    fn push_text(&mut self, text: &str) {
+        for c in text.chars() {
+            if self.preformatted == 0 && c.is_ascii_whitespace() {
+                self.pending_space = true;
+                continue;
+            }
+            if self.line_breaks > 0 {
+                // Nothing goes before the first line
+                if !self.output.is_empty() {
+                    self.output.push_str(&"\n".repeat(self.line_breaks));
+                }
+                self.line_breaks = 0;
+            } else if self.pending_space && !self.output.is_empty() && !self.output.ends_with('\n')
+            {
+                self.output.push(' ');
+            }
+            self.pending_space = false;
+            self.output.push(c);
+        }
+    }
+
+    fn finish(self) -> String {
+        self.output
+    }
+}
+
+/// Elements whose whitespace is kept as written.
+const PREFORMATTED: &[&str] = &["pre", "listing", "xmp", "plaintext", "textarea"];
+
+/// How many line breaks go around an element.
+fn line_breaks(tag: &str, display: &Display) -> usize {
+    match (tag, display) {
+        ("p", _) => 2,
+        (_, Display::Block) => 1,
+        _ => 0,
+    }
+}
+
+impl Visitor for TextExtractor {
+    fn enter(&mut self, dom: &DOM, id: NodeId) -> bool {
+        match &dom.node(id).element {
+            DOMElement::Text { text, .. } => self.push_text(text),
+            DOMElement::View { tag, style, .. } => {
+                match tag.as_str() {
+                    "br" => {
+                        // A line break is kept even at the start of a line
+                        self.output.push_str(&"\n".repeat(self.line_breaks.max(1)));
+                        self.line_breaks = 0;
+                        self.pending_space = false;
+                    }
+                    tag if PREFORMATTED.contains(&tag) => self.preformatted += 1,
+                    _ => {}
+                }
+                self.boundary(line_breaks(tag, &style.display));
+            }
+        }
+        true
+    }
+
+    fn leave(&mut self, dom: &DOM, id: NodeId) {
+        if let DOMElement::View { tag, style, .. } = &dom.node(id).element {
+            if PREFORMATTED.contains(&tag.as_str()) {
+                self.preformatted -= 1;
+            }
+            self.boundary(line_breaks(tag, &style.display));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    /// The tag of every node, or the text of text nodes.
+    fn names(dom: &DOM, ids: impl IntoIterator<Item = NodeId>) -> Vec<String> {
+        ids.into_iter()
+            .map(|id| match &dom.node(id).element {
+                DOMElement::Text { text, .. } => text.clone(),
+                element => element.tag().to_string(),
+            })
+            .collect()
+    }
+
+    fn find(dom: &DOM, selector: &str) -> NodeId {
+        dom.query_selector(selector).unwrap().unwrap()
+    }
+
+    #[test]
+    fn walks_cover_every_root() {
+        let dom = DOM::construct_dom(parser::parse("<p>a<b>b</b></p><ul><li>c</ul>"));
+        assert_eq!(dom.roots().len(), 2);
+        assert_eq!(
+            names(&dom, dom.pre_order()),
+            ["p", "a", "b", "b", "ul", "li", "c"]
+        );
+        assert_eq!(
+            names(&dom, dom.post_order()),
+            ["a", "b", "b", "p", "c", "li", "ul"]
+        );
+    }
+
+    #[test]
+    fn walks_of_a_subtree_stay_inside_it() {
+        let dom = DOM::construct_dom(parser::parse("<div><p>a<b>b</b></p><p>c</p></div><p>d</p>"));
+        let first = find(&dom, "p");
+        assert_eq!(names(&dom, dom.descendants(first)), ["a", "b", "b"]);
+        assert_eq!(
+            names(&dom, dom.descendants_post_order(first)),
+            ["a", "b", "b", "p"]
+        );
+        // A leaf is its own whole subtree
+        let leaf = dom.children(first).next().unwrap();
+        assert_eq!(dom.descendants(leaf).count(), 0);
+        assert_eq!(names(&dom, dom.descendants_post_order(leaf)), ["a"]);
+    }
+
+    #[test]
+    fn ancestors_go_up_to_the_root() {
+        let dom = DOM::construct_dom(parser::parse("<div><p>a<b>b</b></p></div><p>c</p>"));
+        let bold = find(&dom, "b");
+        assert_eq!(names(&dom, dom.ancestors(bold)), ["p", "div"]);
+        assert_eq!(dom.ancestors(dom.roots()[1]).count(), 0);
+    }
+
+    #[test]
+    fn inner_text_breaks_lines_around_blocks() {
+        let dom = DOM::construct_dom(parser::parse(
+            "<div>  Some <b>bold</b>\n text <p>A paragraph</p>after<ul><li>one<li>two</ul>\
+             line<br>break<br><br>twice</div>",
+        ));
+        assert_eq!(
+            dom.inner_text(find(&dom, "div")),
+            "Some bold text\n\nA paragraph\n\nafter\none\ntwo\nline\nbreak\n\ntwice"
+        );
+    }
+
+    #[test]
+    fn inner_text_keeps_preformatted_whitespace() {
+        let dom = DOM::construct_dom(parser::parse(
+            "<div>a   b<pre>  x\n\n   y </pre>c   d</div>",
+        ));
+        assert_eq!(dom.inner_text(find(&dom, "div")), "a b\n  x\n\n   y \nc d");
+    }
+
+    #[test]
+    fn links_have_their_text() {
+        let dom = DOM::construct_dom(parser::parse(
+            "<p><a href=\"/a\">First  <b>link</b></a> <a name=\"x\">anchor</a>\
+             <a href=\"https://example.com/\"><img alt=\"\"></a></p>",
+        ));
+        assert_eq!(
+            dom.links(),
+            [
+                ("/a".to_string(), "First link".to_string()),
+                ("https://example.com/".to_string(), String::new()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use crate::{
+    diagnostics::{DiagnosticKind, ParseDiagnostic},
+    html::{is_void_element, HTMLElement, Span},
+    parser::Token,
+};
+
+/// Elements whose start tag closes an open `<p>`.
+fn closes_p(name: &str) -> bool {
+    matches!(
+        name,
+        "address"
+            | "article"
+            | "aside"
+            | "blockquote"
+            | "center"
+            | "details"
+            | "dialog"
+            | "dir"
+            | "div"
+            | "dl"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "header"
+            | "hgroup"
+            | "hr"
+            | "listing"
+            | "main"
+            | "menu"
+            | "nav"
+            | "ol"
+            | "p"
+            | "plaintext"
+            | "pre"
+            | "section"
+            | "summary"
+            | "table"
+            | "ul"
+            | "xmp"
+            | "li"
+            | "dd"
+            | "dt"
+    )
+}
+
+/// Elements that stop the search for an implicitly closed `<li>`, `<dt>` or `<dd>`.
+fn is_special(name: &str) -> bool {
+    matches!(
+        name,
+        "address"
+            | "article"
+            | "aside"
+            | "blockquote"
+            | "body"
+            | "center"
+            | "details"
+            | "dialog"
+            | "dir"
+            | "div"
+            | "dl"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "head"
+            | "header"
+            | "hgroup"
+            | "html"
+            | "listing"
+            | "main"
+            | "menu"
+            | "nav"
+            | "ol"
+            | "p"
+            | "pre"
+            | "section"
+            | "summary"
+            | "table"
+            | "td"
+            | "th"
+            | "ul"
+            | "xmp"
+    )
+}
+
+/// Elements that end the scope an end tag can close elements in.
+fn is_scope_boundary(name: &str) -> bool {
+    matches!(
+        name,
+        "applet" | "caption" | "html" | "table" | "td" | "th" | "marquee" | "object" | "template"
+    )
+}
+
+fn is_formatting(name: &str) -> bool {
+    matches!(
+        name,
+        "a" | "b"
+            | "big"
+            | "code"
+            | "em"
+            | "font"
+            | "i"
+            | "nobr"
+            | "s"
+            | "small"
+            | "strike"
+            | "strong"
+            | "tt"
+            | "u"
+    )
+}
+
+fn is_heading(name: &str) -> bool {
+    matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+}
+
+/// Elements that may be closed implicitly without it being an error.
+fn has_optional_end_tag(name: &str) -> bool {
+    is_void_element(name)
+        || matches!(
+            name,
+            "html"
+                | "head"
+                | "body"
+                | "p"
+                | "li"
+                | "dt"
+                | "dd"
+                | "option"
+                | "optgroup"
+                | "colgroup"
+                | "caption"
+                | "thead"
+                | "tbody"
+                | "tfoot"
+                | "tr"
+                | "td"
+                | "th"
+                | "rb"
+                | "rt"
+                | "rtc"
+                | "rp"
+        )
+}
+
+#[derive(Debug)]
+struct OpenElement {
+    name: String,
+    tag: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<HTMLElement>,
+    span: Span,
+    /// Reopened by `reconstruct_formatting`, its misnesting is already reported
+    reopened: bool,
+}
+
+impl OpenElement {
+    fn new(tag: String, attributes: Vec<(String, String)>, span: Span) -> Self {
+        Self {
+            name: tag.to_ascii_lowercase(),
+            tag,
+            attributes,
+            children: vec![],
+            span,
+            reopened: false,
+        }
+    }
+}
+
+/// Builds a tree of `HTMLElement`s out of a token stream, in the spirit of the
+/// HTML5 tree construction stage: end tags may be implied, misnested
+/// formatting elements are reopened and stray end tags are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct TreeBuilder {
+    document: Vec<HTMLElement>,
+    open_elements: Vec<OpenElement>,
+    /// Formatting elements that were closed implicitly and get reopened before
+    /// the next inline content.
+    active_formatting: Vec<OpenElement>,
+    /// Span of the token being processed
+    span: Span,
+    diagnostics: Vec<ParseDiagnostic>,
+}
+
+impl TreeBuilder {
+    pub(crate) fn process(&mut self, token: Token, span: Span) {
+        self.span = span;
+        match token {
+            Token::StartTag {
+                tag,
+                attributes,
+                self_closing,
+            } => self.start_tag(tag, attributes, self_closing),
+            Token::EndTag { tag } => self.end_tag(&tag.to_ascii_lowercase()),
+            Token::Text(text) => self.text(text),
+            Token::Comment(text) => self.insert(HTMLElement::Comment { text, span }),
+            // A doctype inside an element is ignored
+            Token::Doctype(text) => {
+                if self.open_elements.is_empty() {
+                    self.insert(HTMLElement::Doctype { text, span });
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> (Vec<HTMLElement>, Vec<ParseDiagnostic>) {
+        while let Some(element) = self.pop(self.span.end) {
+            self.report_missing_close_tag(&element);
+        }
+        (self.document, self.diagnostics)
+    }
+
+    /// The tree built so far, with every open element closed where the input
+    /// currently ends.
+    pub(crate) fn snapshot(&self) -> Vec<HTMLElement> {
+        let mut document = self.document.clone();
+        let mut open = None;
+        for element in self.open_elements.iter().rev() {
+            let mut children = element.children.clone();
+            children.extend(open.take());
+            let span = Span {
+                end: self.span.end,
+                ..element.span
+            };
+            open = Some(HTMLElement::element(
+                element.tag.clone(),
+                element.attributes.clone(),
+                children,
+                span,
+            ));
+        }
+        document.extend(open);
+        document
+    }
+
+    fn start_tag(&mut self, tag: String, attributes: Vec<(String, String)>, self_closing: bool) {
+        let element = OpenElement::new(tag, attributes, self.span);
+        let name = element.name.as_str();
+
+        match name {
+            "li" => self.close_list_item(&["li"]),
+            "dt" | "dd" => self.close_list_item(&["dt", "dd"]),
+            "td" | "th" => self.close_table_part(&["td", "th"], &["tr"]),
+            "tr" => self.close_table_part(&["tr"], &[]),
+            _ => {}
+        }
+
+        if closes_p(name) && self.in_scope("p", &["button"]) {
+            self.close("p");
+        }
+
+        if is_heading(name)
+            && self
+                .current()
+                .is_some_and(|current| is_heading(&current.name))
+        {
+            let heading = self.current().unwrap().name.clone();
+            self.close(&heading);
+        }
+
+        if name == "a" && self.in_scope("a", &[]) {
+            self.close("a");
+            self.active_formatting.retain(|element| element.name != "a");
+        }
+
+        if !closes_p(name) {
+            self.reconstruct_formatting();
+        }
+
+        let void = is_void_element(name);
+        self.open_elements.push(element);
+        if void || self_closing {
+            self.pop(self.span.end);
+        }
+    }
+
+    fn end_tag(&mut self, name: &str) {
+        if let Some(index) = self.active_formatting.iter().rposition(|e| e.name == name) {
+            self.active_formatting.remove(index);
+        }
+
+        let extra_boundaries: &[&str] = match name {
+            "p" => &["button"],
+            "li" => &["ol", "ul"],
+            _ => &[],
+        };
+        let in_scope = if matches!(name, "table" | "thead" | "tbody" | "tfoot" | "tr") {
+            self.in_table_scope(name)
+        } else {
+            self.in_scope(name, extra_boundaries)
+        };
+        if !in_scope {
+            self.report(DiagnosticKind::StrayEndTag {
+                tag: name.to_string(),
+            });
+            return;
+        }
+
+        let current = self.current().unwrap();
+        if current.name != name && !has_optional_end_tag(&current.name) {
+            self.report(DiagnosticKind::MismatchedEndTag {
+                expected: current.name.clone(),
+                found: name.to_string(),
+            });
+        }
+
+        let reopen = is_formatting(name);
+        loop {
+            let closes_target = self.current().is_some_and(|current| current.name == name);
+            let end = if closes_target {
+                self.span.end
+            } else {
+                self.span.start
+            };
+            let Some(element) = self.pop(end) else {
+                break;
+            };
+            if closes_target {
+                break;
+            }
+            if reopen && is_formatting(&element.name) {
+                self.active_formatting.insert(0, element);
+            }
+        }
+    }
+
+    fn text(&mut self, text: String) {
+        let end = self.span.end;
+        let children = match self.open_elements.last_mut() {
+            Some(current) => &mut current.children,
+            None => &mut self.document,
+        };
+        if let Some(HTMLElement::Text {
+            text: previous,
+            span,
+        }) = children.last_mut()
+        {
+            previous.push_str(&text);
+            span.end = end;
+            return;
+        }
+        // Only ASCII whitespace is insignificant, a lone `&nbsp;` is content,
+        // and only before the document and between structural elements
+        if text.chars().all(|c| c.is_ascii_whitespace()) && self.ignores_whitespace() {
+            return;
+        }
+
+        self.reconstruct_formatting();
+        self.insert(HTMLElement::text_node(text, self.span));
+    }
+
+    /// Whether whitespace-only text would land where it can't be rendered,
+    /// like before the first element or between table rows.
+    fn ignores_whitespace(&self) -> bool {
+        match self.current() {
+            Some(current) => matches!(
+                current.name.as_str(),
+                "html" | "head" | "table" | "thead" | "tbody" | "tfoot" | "tr" | "colgroup"
+            ),
+            None => !self
+                .document
+                .iter()
+                .any(|node| matches!(node, HTMLElement::Element { .. } | HTMLElement::Text { .. })),
+        }
+    }
+
+    /// Close the nearest open `<li>` (or `<dt>`/`<dd>`) unless a special
+    /// element like a nested list comes first.
+    fn close_list_item(&mut self, names: &[&str]) {
+        let item = self
+            .open_elements
+            .iter()
+            .rev()
+            .take_while(|element| {
+                names.contains(&element.name.as_str())
+                    || !is_special(&element.name)
+                    || matches!(element.name.as_str(), "address" | "div" | "p")
+            })
+            .find(|element| names.contains(&element.name.as_str()))
+            .map(|element| element.name.clone());
+
+        if let Some(name) = item {
+            self.close(&name);
+        }
+    }
+
+    /// Close the nearest open cell (or row) of the current table, unless one of
+    /// `boundaries`, like the row a new cell goes in, comes first.
+    fn close_table_part(&mut self, names: &[&str], boundaries: &[&str]) {
+        let part = self
+            .open_elements
+            .iter()
+            .rev()
+            .take_while(|element| {
+                element.name != "table" && !boundaries.contains(&element.name.as_str())
+            })
+            .find(|element| names.contains(&element.name.as_str()))
+            .map(|element| element.name.clone());
+
+        if let Some(name) = part {
+            self.close(&name);
+        }
+    }
+
+    /// Implicitly closes elements up to and including the nearest `name`.
+    fn close(&mut self, name: &str) {
+        while let Some(element) = self.pop(self.span.start) {
+            self.report_missing_close_tag(&element);
+            if element.name == name {
+                break;
+            }
+        }
+    }
+
+    fn in_scope(&self, name: &str, extra_boundaries: &[&str]) -> bool {
+        for element in self.open_elements.iter().rev() {
+            if element.name == name {
+                return true;
+            }
+            if is_scope_boundary(&element.name) || extra_boundaries.contains(&element.name.as_str())
+            {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Like `in_scope`, but cells don't hide the table parts around them, so
+    /// `</tr>` or `</table>` can close an open `<td>`.
+    fn in_table_scope(&self, name: &str) -> bool {
+        for element in self.open_elements.iter().rev() {
+            if element.name == name {
+                return true;
+            }
+            if matches!(element.name.as_str(), "html" | "table" | "template") {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn reconstruct_formatting(&mut self) {
+        let span = self.span;
+        let formatting = std::mem::take(&mut self.active_formatting);
+        self.open_elements
+            .extend(formatting.into_iter().map(|element| OpenElement {
+                span,
+                reopened: true,
+                ..element
+            }));
+    }
+
+    fn current(&self) -> Option<&OpenElement> {
+        self.open_elements.last()
+    }
+
+    fn report(&mut self, kind: DiagnosticKind) {
+        self.diagnostics.push(ParseDiagnostic::new(kind, self.span));
+    }
+
+    fn report_missing_close_tag(&mut self, element: &OpenElement) {
+        if !element.reopened && !has_optional_end_tag(&element.name) {
+            self.diagnostics.push(ParseDiagnostic::new(
+                DiagnosticKind::MissingCloseTag {
+                    tag: element.name.clone(),
+                },
+                element.span,
+            ));
+        }
+    }
+
+    /// Pops the current node, ending at byte offset `end`, and attaches it to
+    /// its parent. Returns a copy of it without children.
+    fn pop(&mut self, end: usize) -> Option<OpenElement> {
+        let OpenElement {
+            name,
+            tag,
+            attributes,
+            children,
+            span,
+            reopened,
+        } = self.open_elements.pop()?;
+        let popped = OpenElement {
+            name,
+            tag: tag.clone(),
+            attributes: attributes.clone(),
+            children: vec![],
+            span,
+            reopened,
+        };
+        let span = Span {
+            end: end.max(span.end),
+            ..span
+        };
+        self.insert(HTMLElement::element(tag, attributes, children, span));
+        Some(popped)
+    }
+
+    fn insert(&mut self, node: HTMLElement) {
+        match self.open_elements.last_mut() {
+            Some(current) => current.children.push(node),
+            None => self.document.push(node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        diagnostics::DiagnosticKind,
+        parser,
+        serializer::{to_html, SerializeOptions},
+    };
+
+    fn tree(html: &str) -> String {
+        to_html(&parser::parse(html), SerializeOptions::default())
+    }
+
+    fn diagnostics(html: &str) -> Vec<DiagnosticKind> {
+        let (_, diagnostics) = parser::parse_with_diagnostics(html);
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.kind)
+            .collect()
+    }
+
+    #[test]
+    fn block_start_tags_imply_the_end_of_a_paragraph() {
+        assert_eq!(
+            tree("<p>a<p>b<div>c</div><p>d<ul><li>e</ul>"),
+            "<p>a</p><p>b</p><div>c</div><p>d</p><ul><li>e</li></ul>"
+        );
+        assert!(diagnostics("<p>a<p>b<div>c</div>").is_empty());
+        // Unlike `<p>`, the `<b>` needs an end tag
+        assert_eq!(tree("<p>a<b>b<h1>c</h1>"), "<p>a<b>b</b></p><h1>c</h1>");
+        assert_eq!(
+            diagnostics("<p>a<b>b<h1>c</h1>"),
+            vec![DiagnosticKind::MissingCloseTag {
+                tag: "b".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn list_items_close_the_previous_item_but_not_an_outer_one() {
+        assert_eq!(
+            tree("<ul><li>a<li>b<ul><li>c<li>d</ul><li>e</ul>"),
+            "<ul><li>a</li><li>b<ul><li>c</li><li>d</li></ul></li><li>e</li></ul>"
+        );
+        assert_eq!(
+            tree("<dl><dt>a<dd>b<dt>c<dd>d</dl>"),
+            "<dl><dt>a</dt><dd>b</dd><dt>c</dt><dd>d</dd></dl>"
+        );
+    }
+
+    #[test]
+    fn misnested_formatting_elements_are_reopened() {
+        assert_eq!(tree("<b>1<i>2</b>3</i>4"), "<b>1<i>2</i></b><i>3</i>4");
+        assert_eq!(
+            tree("<a><b>1<i>2</a>3</i>"),
+            "<a><b>1<i>2</i></b></a><b><i>3</i></b>"
+        );
+        assert_eq!(
+            diagnostics("<b>1<i>2</b>3</i>4"),
+            vec![DiagnosticKind::MismatchedEndTag {
+                expected: "i".to_string(),
+                found: "b".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn stray_end_tags_are_dropped() {
+        assert_eq!(tree("<div>a</span>b</div></p>"), "<div>ab</div>");
+        assert_eq!(
+            diagnostics("<div>a</span>b</div>"),
+            vec![DiagnosticKind::StrayEndTag {
+                tag: "span".to_string(),
+            }]
+        );
+        // An end tag can't close an element outside the table cell it's in
+        assert_eq!(
+            tree("<b><table><tr><td>a</b>b</td></tr></table>"),
+            "<b><table><tr><td>ab</td></tr></table></b>"
+        );
+    }
+
+    #[test]
+    fn table_cells_and_rows_are_closed_implicitly() {
+        assert_eq!(
+            tree("<table><tr><td>a<td>b</table>"),
+            "<table><tr><td>a</td><td>b</td></tr></table>"
+        );
+        assert_eq!(
+            tree("<table><tr><th>a<td>b<tr><td>c</tr></table>"),
+            "<table><tr><th>a</th><td>b</td></tr><tr><td>c</td></tr></table>"
+        );
+        assert_eq!(
+            tree("<table><tr><td><table><tr><td>a<td>b</table>c<td>d</table>"),
+            "<table><tr><td><table><tr><td>a</td><td>b</td></tr></table>c</td><td>d</td></tr></table>"
+        );
+        assert!(diagnostics("<table><tr><td>a<td>b<tr><td>c</table>").is_empty());
+    }
+
+    #[test]
+    fn void_elements_close_at_once_whatever_their_case() {
+        assert_eq!(
+            tree("<P>a<IMG SRC=x.gif ALT=x>b<Br>c<NEXTID N=55><isindex>d<HR>e"),
+            "<p>a<img src=\"x.gif\" alt=\"x\">b<br>c<nextid n=\"55\"><isindex>d</p><hr>e"
+        );
+        // None of them waits for an end tag that never comes
+        assert!(diagnostics("<IMG SRC=x.gif><Input type=text><META charset=utf-8>").is_empty());
+    }
+}